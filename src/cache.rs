@@ -0,0 +1,245 @@
+//! Persistent on-disk cache for rendered blocks.
+//!
+//! This module provides a [`RenderCache`] that stores the output of rendered
+//! blocks keyed by a hash of their content, so that unchanged blocks do not
+//! need to be compiled again on the next build.
+//!
+//! The cache is invalidated as a whole when the version of this crate or of
+//! Typst, or the set of loaded fonts changes, and the least recently used entries are evicted once
+//! the total size exceeds the configured limit.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use typst::text::Font;
+
+/// Name of the index file inside the cache directory.
+const INDEX_FILE: &str = "index.json";
+
+/// A single cached render.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Size of the cached output in bytes.
+    size: u64,
+    /// Logical timestamp of the last access, used for LRU eviction.
+    last_used: u64,
+    /// Project files the render depends on, with their content hashes.
+    dependencies: Vec<(PathBuf, String)>,
+    /// Extension of the file storing the output, e.g. `svg`.
+    extension: String,
+}
+
+/// The persisted index of the cache.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    /// Version of this crate that produced the entries.
+    version: String,
+    /// Version of the Typst compiler that produced the entries.
+    typst_version: String,
+    /// Fingerprint of the fonts available when the entries were produced.
+    fonts: String,
+    /// Logical clock, incremented on every access.
+    clock: u64,
+    /// Cached entries by key.
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// A content-addressed cache of rendered blocks stored on disk.
+///
+/// # Example
+///
+/// ```ignore
+/// let cache = RenderCache::open(".typst-cache/renders", &compiler.fonts, 256 << 20)?;
+/// let key = RenderCache::key(&[source, "auto"]);
+/// cache.insert(&key, "svg", &svg, &dependencies);
+/// if let Some(svg) = cache.get(&key) {
+///     // reuse the cached output
+/// }
+/// ```
+pub struct RenderCache {
+    /// Directory holding the index and the cached outputs.
+    dir: PathBuf,
+    /// Maximum total size of the cached outputs in bytes.
+    max_size: u64,
+    /// The in-memory index, written back by [`RenderCache::save`].
+    index: Mutex<CacheIndex>,
+}
+
+impl RenderCache {
+    /// Opens the cache in `dir`, creating it if it doesn't exist.
+    ///
+    /// If the cache was produced by another version of this crate or of
+    /// Typst, or with another set of fonts, all existing entries are
+    /// discarded.
+    pub fn open(dir: impl Into<PathBuf>, fonts: &[Font], max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let version = env!("CARGO_PKG_VERSION").to_string();
+        let typst_version = crate::compiler::Compiler::typst_version();
+        let fonts = fonts_fingerprint(fonts);
+
+        let index = fs::read(dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice::<CacheIndex>(&data).ok())
+            .unwrap_or_default();
+
+        let index = if index.version == version
+            && index.typst_version == typst_version
+            && index.fonts == fonts
+        {
+            index
+        } else {
+            if !index.entries.is_empty() {
+                debug!("Version or fonts changed, clearing render cache");
+            }
+            for (key, entry) in &index.entries {
+                fs::remove_file(entry_path(&dir, key, &entry.extension)).ok();
+            }
+            CacheIndex {
+                version,
+                typst_version,
+                fonts,
+                ..Default::default()
+            }
+        };
+
+        Ok(Self {
+            dir,
+            max_size,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Computes the cache key for the given parts.
+    ///
+    /// The parts should include everything that affects the output, such as
+    /// the full Typst source and any post-processing options.
+    pub fn key(parts: &[&str]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Looks up a cached output, returning `None` on a miss.
+    ///
+    /// An entry whose dependencies changed since it was stored is treated
+    /// as a miss.
+    pub fn get(&self, key: &str) -> Option<String> {
+        // Hash the dependencies without holding the lock, so that other
+        // blocks can use the cache in the meantime
+        let entry = self.index.lock().unwrap().entries.get(key)?.clone();
+        let fresh = entry
+            .dependencies
+            .iter()
+            .all(|(path, hash)| file_hash(path).as_deref() == Some(hash.as_str()));
+        let output = fresh
+            .then(|| fs::read_to_string(entry_path(&self.dir, key, &entry.extension)).ok())
+            .flatten();
+
+        let mut index = self.index.lock().unwrap();
+        let Some(output) = output else {
+            index.entries.remove(key);
+            return None;
+        };
+
+        index.clock += 1;
+        let clock = index.clock;
+        if let Some(entry) = index.entries.get_mut(key) {
+            entry.last_used = clock;
+        }
+        Some(output)
+    }
+
+    /// Stores an output in the cache, evicting old entries if needed.
+    ///
+    /// The output is stored in a file with the given `extension`, matching
+    /// its format.
+    pub fn insert(&self, key: &str, extension: &str, output: &str, dependencies: &[PathBuf]) {
+        let dependencies = dependencies
+            .iter()
+            .filter_map(|path| Some((path.clone(), file_hash(path)?)))
+            .collect();
+
+        if let Err(e) = fs::write(entry_path(&self.dir, key, extension), output) {
+            warn!("Failed to write render cache entry: {e}");
+            return;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let entry = CacheEntry {
+            size: output.len() as u64,
+            last_used: index.clock,
+            dependencies,
+            extension: extension.to_string(),
+        };
+        if let Some(old) = index.entries.insert(key.to_string(), entry) {
+            if old.extension != extension {
+                fs::remove_file(entry_path(&self.dir, key, &old.extension)).ok();
+            }
+        }
+
+        // Evict the least recently used entries until we fit the limit
+        let mut total_size: u64 = index.entries.values().map(|entry| entry.size).sum();
+        if total_size <= self.max_size {
+            return;
+        }
+        let mut entries = index
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        for (_, oldest) in entries {
+            if total_size <= self.max_size {
+                break;
+            }
+            if let Some(entry) = index.entries.remove(&oldest) {
+                total_size -= entry.size;
+                fs::remove_file(entry_path(&self.dir, &oldest, &entry.extension)).ok();
+            }
+        }
+    }
+
+    /// Writes the index back to disk.
+    pub fn save(&self) -> io::Result<()> {
+        let index = self.index.lock().unwrap();
+        let data = serde_json::to_vec(&*index)?;
+        fs::write(self.dir.join(INDEX_FILE), data)
+    }
+}
+
+/// Path of the file storing the output for `key`.
+fn entry_path(dir: &Path, key: &str, extension: &str) -> PathBuf {
+    dir.join(format!("{key}.{extension}"))
+}
+
+/// Hashes the content of a file, returning `None` if it cannot be read.
+fn file_hash(path: &Path) -> Option<String> {
+    let data = fs::read(path).ok()?;
+    Some(format!("{:x}", Sha256::digest(data)))
+}
+
+/// Computes a fingerprint of the loaded fonts.
+fn fonts_fingerprint(fonts: &[Font]) -> String {
+    let mut hasher = Sha256::new();
+    for font in fonts {
+        if let Ok(info) = serde_json::to_vec(font.info()) {
+            hasher.update(info);
+        }
+        hasher.update(font.index().to_le_bytes());
+        hasher.update((font.data().len() as u64).to_le_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
@@ -5,52 +5,300 @@
 //!
 //! Highly inspired by the [typst-bot](https://github.com/mattfbacon/typst-bot).
 
-use std::{collections::HashMap, fmt, io::Write, path::PathBuf, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, LazyLock, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
 
 use codespan_reporting::{
     diagnostic::{Diagnostic, Label},
+    files::Files,
     term,
 };
-use tracing::{error, warn};
+use serde::Serialize;
 use typst::{
-    diag::{
-        eco_format, FileError, FileResult, PackageError, PackageResult, SourceDiagnostic, Warned,
-    },
-    foundations::{Bytes, Datetime},
-    layout::PagedDocument,
+    diag::{eco_format, FileError, FileResult, PackageResult, SourceDiagnostic, Warned},
+    foundations::{Bytes, Datetime, Dict, NativeElement, Selector, Value},
+    layout::{Abs, PagedDocument, Position},
+    math::EquationElem,
     syntax::{package::PackageSpec, FileId, Lines, Source, Span, VirtualPath},
     text::{Font, FontBook},
-    utils::LazyHash,
-    Library, LibraryExt, World, WorldExt,
+    utils::{LazyHash, PicoStr},
+    Feature, Library, LibraryExt, World, WorldExt,
 };
+use typst_html::HtmlDocument;
 use typst_svg::svg;
 
+use crate::lock::PackageLock;
+use crate::mathml;
+use crate::package::{not_found, PackageChain, PackageProvider};
+
+/// Severity of a [`TypstDiagnostic`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// A fatal error.
+    Error,
+    /// A non-fatal warning.
+    Warning,
+}
+
+impl From<typst::diag::Severity> for Severity {
+    fn from(severity: typst::diag::Severity) -> Self {
+        match severity {
+            typst::diag::Severity::Error => Severity::Error,
+            typst::diag::Severity::Warning => Severity::Warning,
+        }
+    }
+}
+
+/// A diagnostic reported by Typst, with its location mapped back to the
+/// markdown file where possible.
+#[derive(Debug, Clone, Serialize)]
+pub struct TypstDiagnostic {
+    /// Whether this is an error or a warning.
+    pub severity: Severity,
+    /// The main message of the diagnostic.
+    pub message: String,
+    /// Additional hints to help fixing the problem.
+    pub hints: Vec<String>,
+    /// The file the diagnostic points to.
+    ///
+    /// This is the chapter's source path for diagnostics in a block, or the
+    /// package file for diagnostics in a package.
+    pub file: Option<String>,
+    /// The line the diagnostic points to (1-indexed).
+    pub line: Option<usize>,
+    /// The column the diagnostic points to (1-indexed).
+    pub column: Option<usize>,
+    /// The offending source code.
+    pub snippet: Option<String>,
+    /// The diagnostic formatted for humans, including the source excerpt.
+    #[serde(skip)]
+    pub rendered: String,
+}
+
+impl fmt::Display for TypstDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for hint in &self.hints {
+            write!(f, "\nhint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
 /// Errors that can occur during Typst compilation.
 #[derive(Debug)]
 pub enum CompileError {
     /// Typst compilation failed with diagnostics.
     ///
-    /// Contains a formatted string of the compilation errors.
-    Compilation(String),
+    /// Contains the errors and warnings reported by Typst.
+    Compilation(Vec<TypstDiagnostic>),
     /// Internal lock was poisoned.
     ///
     /// This should not happen in normal operation and indicates a panic
     /// occurred while holding a lock.
     #[allow(dead_code)]
     LockPoisoned,
+    /// The source compiled, but cannot be expressed in the requested
+    /// [`Format`].
+    ///
+    /// Contains a description of the unsupported construct.
+    Unsupported(String),
 }
 
 impl fmt::Display for CompileError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CompileError::Compilation(msg) => write!(f, "Typst compilation error: {}", msg),
+            CompileError::Compilation(diagnostics) => {
+                let errors = diagnostics
+                    .iter()
+                    .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                write!(f, "Typst compilation error: {}", errors.join("\n"))
+            }
             CompileError::LockPoisoned => write!(f, "Internal error: lock poisoned"),
+            CompileError::Unsupported(construct) => write!(f, "Unsupported content: {}", construct),
         }
     }
 }
 
 impl std::error::Error for CompileError {}
 
+/// Output format of [`Compiler::render`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// All pages as concatenated SVG images.
+    #[default]
+    Svg,
+    /// The first equation of the document as MathML, compiled through
+    /// Typst's HTML target.
+    Mathml,
+    /// The body of the document as HTML, compiled through Typst's HTML
+    /// target.
+    Html,
+    /// All pages as PNG images at [`Compiler::ppi`], see [`Rendered::pngs`].
+    Png,
+}
+
+/// A page rendered to PNG.
+#[derive(Debug, Clone)]
+pub struct PngPage {
+    /// The encoded PNG image.
+    pub data: Vec<u8>,
+    /// Width of the page in pt.
+    pub width: f64,
+    /// Height of the page in pt.
+    pub height: f64,
+}
+
+/// The output of a successful [`Compiler::render`] call.
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    /// The rendered output in the requested [`Format`].
+    ///
+    /// Empty for [`Format::Png`], whose output is in [`Rendered::pngs`].
+    pub output: String,
+    /// The rendered pages for [`Format::Png`].
+    pub pngs: Vec<PngPage>,
+    /// Project files (outside of packages) read during compilation.
+    ///
+    /// Callers caching the output should treat it as stale once any of
+    /// these files changes.
+    pub dependencies: Vec<PathBuf>,
+    /// Warnings reported by Typst.
+    pub warnings: Vec<TypstDiagnostic>,
+    /// Distance from the baseline at the end of the source to the bottom of
+    /// its page, in units of the text size.
+    ///
+    /// Only available for [`Format::Svg`] and [`Format::Png`] outputs of
+    /// sources ending with [`TEXT_SIZE_PROBE`].
+    pub baseline: Option<f64>,
+    /// The text size in effect at the end of the source, in pt.
+    ///
+    /// Only available for [`Format::Svg`] and [`Format::Png`] outputs of
+    /// sources ending with [`TEXT_SIZE_PROBE`].
+    pub text_size: Option<f64>,
+}
+
+/// Typst code which, appended to a source, records the text size in effect
+/// so that [`Rendered::text_size`] and [`Rendered::baseline`] are available.
+///
+/// It produces no visible output and does not affect the layout.
+pub const TEXT_SIZE_PROBE: &str =
+    "#context [#metadata(text.size.to-absolute()) <typst-math-text-size>]";
+
+/// A byte range of a Typst source copied verbatim from the markdown file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Byte range in the Typst source.
+    pub typst: Range<usize>,
+    /// Byte offset in the markdown file where the range starts.
+    pub markdown: usize,
+}
+
+/// Describes where a Typst source comes from.
+///
+/// This is used to resolve relative file accesses and to map diagnostics
+/// back to the markdown file the source was extracted from.
+#[derive(Clone, Default)]
+pub struct Origin {
+    /// Name to use in diagnostics (e.g., chapter name).
+    pub name: Option<String>,
+    /// File to report as [`TypstDiagnostic::file`] for diagnostics in the
+    /// source (e.g., the chapter's source path), instead of `name`.
+    pub file: Option<String>,
+    /// Path of the source within [`Compiler::root`], used to resolve
+    /// relative file accesses (e.g., the chapter's source path).
+    pub path: Option<PathBuf>,
+    /// Content of the markdown file.
+    ///
+    /// If `None`, diagnostics point into the Typst source itself.
+    pub markdown: Option<Lines<String>>,
+    /// Byte range of the block in the markdown file.
+    pub span: Range<usize>,
+    /// Length of the preamble in bytes, including the trailing newline.
+    pub preamble_len: usize,
+    /// Which preamble the source starts with, naming it in diagnostics.
+    pub preamble: Preamble,
+    /// Ranges of the Typst source copied verbatim from the markdown file.
+    pub segments: Vec<Segment>,
+}
+
+impl Origin {
+    /// Maps a byte offset in the Typst source to the markdown file.
+    ///
+    /// With `end` set, an offset at the end of a segment is considered
+    /// part of it, so that ranges can be mapped.
+    fn map_offset(&self, offset: usize, end: bool) -> Option<usize> {
+        self.segments
+            .iter()
+            .find(|segment| match end {
+                true => segment.typst.start < offset && offset <= segment.typst.end,
+                false => segment.typst.start <= offset && offset < segment.typst.end,
+            })
+            .map(|segment| segment.markdown + offset - segment.typst.start)
+    }
+
+    /// Maps a byte range in the Typst source to the markdown file.
+    ///
+    /// Ranges outside of the copied segments (e.g. the `$ ` padding added to
+    /// display math) are mapped to the whole block.
+    fn map_range(&self, range: Range<usize>) -> Range<usize> {
+        let Some(start) = self.map_offset(range.start, false) else {
+            return self.span.clone();
+        };
+        let end = self
+            .map_offset(range.end, true)
+            .filter(|&end| end >= start)
+            .or_else(|| {
+                // Clamp to the end of the segment containing the start
+                self.segments
+                    .iter()
+                    .find(|segment| segment.typst.contains(&range.start))
+                    .map(|segment| segment.markdown + segment.typst.len())
+            })
+            .unwrap_or(start);
+        start..end
+    }
+}
+
+/// The preamble a source starts with.
+///
+/// Diagnostics in different preambles have different file names, so that
+/// warnings at the same location are only merged within one preamble.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Preamble {
+    /// The preamble shared by all blocks, named `preamble`.
+    #[default]
+    Shared,
+    /// The preamble of inline math, named `inline-preamble`.
+    Inline,
+    /// The preamble of display math and code blocks, named
+    /// `display-preamble`.
+    Display,
+}
+
+/// File IDs used in diagnostics for spans inside each [`Preamble`].
+static PREAMBLE_IDS: LazyLock<[FileId; 3]> = LazyLock::new(|| {
+    ["preamble", "inline-preamble", "display-preamble"]
+        .map(|name| FileId::new_fake(VirtualPath::new(name)))
+});
+
+impl Preamble {
+    /// Returns the file ID used in diagnostics for spans inside the preamble.
+    fn id(self) -> FileId {
+        PREAMBLE_IDS[self as usize]
+    }
+}
+
 /// Cached file with bytes and optional parsed source.
 struct CachedFile {
     bytes: Bytes,
@@ -62,7 +310,7 @@ struct CachedFile {
 /// This struct holds all the state needed to compile Typst documents:
 /// - Standard library and font book
 /// - Loaded fonts
-/// - File cache for packages and sources
+/// - File cache for packages, project files and sources
 ///
 /// # Example
 ///
@@ -78,8 +326,32 @@ pub struct Compiler {
     pub book: LazyHash<FontBook>,
     /// Loaded font data.
     pub fonts: Vec<Font>,
-    /// Cache directory for downloaded packages.
-    pub cache: PathBuf,
+    /// Resolves the packages imported by sources.
+    ///
+    /// Defaults to the packages of the Typst CLI, see [`PackageChain::typst`].
+    pub packages: PackageChain,
+    /// Lockfile the files of resolved packages are verified against.
+    pub lock: Option<Arc<PackageLock>>,
+    /// Project root used to resolve files that do not belong to a package.
+    ///
+    /// Rooted paths like `/shared/macros.typ` are resolved against this
+    /// directory, and relative paths against the directory of the importing
+    /// file within it.
+    pub root: PathBuf,
+    /// Additional directories project files may resolve to.
+    ///
+    /// Files are only read if their canonical path is inside [`Compiler::root`]
+    /// (or the package directory for package files) or one of these
+    /// directories, so that symlinks cannot be used to escape the project.
+    pub allowed_paths: Vec<PathBuf>,
+    /// Maximum number of pages a document may have, unlimited if `None`.
+    pub max_pages: Option<usize>,
+    /// Maximum size of the output in bytes, unlimited if `None`.
+    ///
+    /// For [`Format::Png`], this is the size of all encoded pages together.
+    pub max_output_bytes: Option<usize>,
+    /// Resolution of PNG output in pixels per inch.
+    pub ppi: f32,
     /// Internal file cache for sources and binary files.
     files: RwLock<HashMap<FileId, CachedFile>>,
 }
@@ -87,10 +359,21 @@ pub struct Compiler {
 impl Default for Compiler {
     fn default() -> Self {
         Self {
-            library: LazyHash::new(Library::default()),
+            // The HTML target is needed for MathML output
+            library: LazyHash::new(
+                Library::builder()
+                    .with_features([Feature::Html].into_iter().collect())
+                    .build(),
+            ),
             book: LazyHash::new(FontBook::default()),
             fonts: Vec::new(),
-            cache: PathBuf::new(),
+            packages: PackageChain::typst(),
+            lock: None,
+            root: PathBuf::new(),
+            allowed_paths: Vec::new(),
+            max_pages: None,
+            max_output_bytes: None,
+            ppi: 144.0,
             files: RwLock::new(HashMap::new()),
         }
     }
@@ -114,100 +397,48 @@ impl Compiler {
     /// # Parameters
     ///
     /// - `source`: The Typst source code to compile
-    /// - `filename`: Optional filename to use in diagnostics (e.g., chapter name)
-    /// - `markdown_line`: The line number in the original markdown file (1-indexed)
-    /// - `preamble_lines`: Number of lines in the preamble before the actual content
-    pub fn wrap_source(
-        &self,
-        source: impl Into<String>,
-        filename: Option<&str>,
-        markdown_line: usize,
-        preamble_lines: usize,
-    ) -> WrapSource<'_> {
+    /// - `origin`: Where the source comes from, see [`Origin`]
+    pub fn wrap_source(&self, source: impl Into<String>, origin: Origin) -> WrapSource<'_> {
         let source_str = source.into();
-        let source = if let Some(name) = filename {
-            let vpath = VirtualPath::new(name);
-            let file_id = FileId::new(None, vpath);
-            Source::new(file_id, source_str)
-        } else {
-            Source::detached(source_str)
+        let source = match (&origin.path, &origin.name) {
+            (Some(path), _) => Source::new(FileId::new(None, VirtualPath::new(path)), source_str),
+            (None, Some(name)) => {
+                Source::new(FileId::new(None, VirtualPath::new(name)), source_str)
+            }
+            (None, None) => Source::detached(source_str),
         };
 
         WrapSource {
             compiler: self,
             source,
+            origin,
             time: time::OffsetDateTime::now_local().unwrap_or(time::OffsetDateTime::now_utc()),
-            markdown_line,
-            preamble_lines,
+            accessed: Mutex::new(HashSet::new()),
         }
     }
 
-    /// Gets the package directory, downloading it if it doesn't exist.
-    ///
-    /// Packages are downloaded from `packages.typst.org` and extracted
-    /// to the cache directory.
-    fn package(&self, package: &PackageSpec) -> PackageResult<PathBuf> {
-        let package_subdir = format!("{}/{}/{}", package.namespace, package.name, package.version);
-        let path = self.cache.join(package_subdir);
-
-        if path.exists() {
-            return Ok(path);
+    /// Returns the version of the Typst compiler, e.g. `0.14.2`.
+    pub fn typst_version() -> String {
+        match typst::foundations::sys::module(Dict::new())
+            .scope()
+            .get("version")
+            .map(|binding| binding.read())
+        {
+            Some(Value::Version(version)) => version.to_string(),
+            _ => String::from("unknown"),
         }
+    }
 
-        // Download the package
-        let package_url = format!(
-            "https://packages.typst.org/{}/{}-{}.tar.gz",
-            package.namespace, package.name, package.version
-        );
-
-        let mut response = reqwest::blocking::get(package_url).map_err(|e| {
-            PackageError::NetworkFailed(Some(eco_format!("{}: {}", package.name, e)))
-        })?;
-
-        let mut compressed = Vec::new();
-        response.copy_to(&mut compressed).map_err(|e| {
-            PackageError::NetworkFailed(Some(eco_format!(
-                "Failed to save package {}: {}",
-                package.name,
-                e
-            )))
-        })?;
-
-        let decompressed = Vec::new();
-        let mut decoder = flate2::write::GzDecoder::new(decompressed);
-        decoder.write_all(&compressed).map_err(|e| {
-            PackageError::MalformedArchive(Some(eco_format!(
-                "Failed to decompress package {}: {}",
-                package.name,
-                e
-            )))
-        })?;
-        decoder.try_finish().map_err(|e| {
-            PackageError::MalformedArchive(Some(eco_format!(
-                "Failed to decompress package {}: {}",
-                package.name,
-                e
-            )))
-        })?;
-        let decompressed = decoder.finish().map_err(|e| {
-            PackageError::MalformedArchive(Some(eco_format!(
-                "Failed to decompress package {}: {}",
-                package.name,
-                e
-            )))
-        })?;
-
-        let mut archive = tar::Archive::new(decompressed.as_slice());
-        archive.unpack(&path).map_err(|e| {
-            std::fs::remove_dir_all(&path).ok();
-            PackageError::MalformedArchive(Some(eco_format!(
-                "Failed to unpack package {}: {}",
-                package.name,
-                e
-            )))
-        })?;
-
-        Ok(path)
+    /// Gets the package directory, fetching it if necessary.
+    fn package(&self, package: &PackageSpec) -> PackageResult<PathBuf> {
+        let dir = self
+            .packages
+            .resolve(package)
+            .map_err(|e| not_found(&self.packages, e))?;
+        if let Some(ref lock) = self.lock {
+            lock.verify_files(package, &dir)?;
+        }
+        Ok(dir)
     }
 
     /// Gets the raw bytes of a file, loading and caching if necessary.
@@ -220,27 +451,57 @@ impl Compiler {
             }
         }
 
-        // File not cached, try to load it
-        if let Some(package) = id.package() {
-            let package_dir = self.package(package)?;
-            let Some(path) = id.vpath().resolve(&package_dir) else {
-                return Err(FileError::NotFound(id.vpath().as_rootless_path().into()));
-            };
-            let contents = std::fs::read(&path).map_err(|e| FileError::from_io(e, &path))?;
-            let bytes = Bytes::new(contents);
+        // File not cached, try to load it from the package or the project root
+        let root = match id.package() {
+            Some(package) => self.package(package)?,
+            None => self.root.clone(),
+        };
+        // Paths escaping the root with `..` do not resolve
+        let Some(path) = id.vpath().resolve(&root) else {
+            return Err(access_denied(id));
+        };
+        // Read the checked path, so that a symlink swapped in afterwards
+        // cannot redirect the read
+        let path = match id.package() {
+            Some(_) => self.check_access(id, &path, &[&root])?,
+            None => {
+                let mut allowed = vec![root.as_path()];
+                allowed.extend(self.allowed_paths.iter().map(PathBuf::as_path));
+                self.check_access(id, &path, &allowed)?
+            }
+        };
+        let contents = std::fs::read(&path).map_err(|e| FileError::from_io(e, &path))?;
+        let bytes = Bytes::new(contents);
 
-            let mut files = self.files.write().unwrap();
-            files.insert(
-                id,
-                CachedFile {
-                    bytes: bytes.clone(),
-                    source: None,
-                },
-            );
-            return Ok(bytes);
-        }
+        let mut files = self.files.write().unwrap();
+        files.insert(
+            id,
+            CachedFile {
+                bytes: bytes.clone(),
+                source: None,
+            },
+        );
+        Ok(bytes)
+    }
 
-        Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
+    /// Checks that `path` does not resolve outside of the `allowed` directories,
+    /// returning the canonical path.
+    ///
+    /// The path is canonicalized first, so symlinks pointing outside of the
+    /// allowed directories are rejected as well.
+    fn check_access(&self, id: FileId, path: &Path, allowed: &[&Path]) -> FileResult<PathBuf> {
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| FileError::from_io(e, path))?;
+        let is_allowed = allowed
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .any(|dir| canonical.starts_with(dir));
+        if is_allowed {
+            Ok(canonical)
+        } else {
+            Err(access_denied(id))
+        }
     }
 
     /// Gets a parsed source file, loading and caching if necessary.
@@ -273,57 +534,332 @@ impl Compiler {
         Ok(source)
     }
 
-    /// Renders Typst source code to SVG.
+    /// Renders Typst source code to the given [`Format`].
     ///
-    /// Compiles the given Typst source and returns the rendered pages
-    /// as concatenated SVG strings.
+    /// Compiles the given Typst source and returns the rendered output,
+    /// together with the project files the source depends on.
     ///
     /// # Parameters
     ///
     /// - `source`: The Typst source code to render
-    /// - `filename`: Optional filename to use in diagnostics (e.g., chapter name)
-    /// - `markdown_line`: The line number in the original markdown file (1-indexed)
-    /// - `preamble_lines`: Number of lines in the preamble before the actual content
+    /// - `origin`: Where the source comes from, see [`Origin`]
+    /// - `format`: The output format, see [`Format`]
     ///
     /// # Errors
     ///
-    /// Returns [`CompileError::Compilation`] if the Typst code fails to compile.
+    /// Returns [`CompileError::Compilation`] with the errors and warnings
+    /// reported by Typst if the Typst code fails to compile, or if the output
+    /// exceeds [`Compiler::max_pages`] or [`Compiler::max_output_bytes`].
+    ///
+    /// Returns [`CompileError::Unsupported`] if the source cannot be
+    /// expressed in the format, e.g. an equation using styles that have no
+    /// MathML counterpart.
     ///
     /// # Example
     ///
     /// ```ignore
     /// let compiler = Compiler::new();
-    /// let svg = compiler.render("$ E = m c^2 $", Some("chapter1.md"), 42, 1)?;
+    /// let rendered = compiler.render("$ E = m c^2 $", Origin::default(), Format::Svg)?;
     /// ```
     pub fn render(
         &self,
         source: impl Into<String>,
-        filename: Option<&str>,
-        markdown_line: usize,
-        preamble_lines: usize,
-    ) -> Result<String, CompileError> {
-        let source = source.into();
-        let world = self.wrap_source(source, filename, markdown_line, preamble_lines);
+        origin: Origin,
+        format: Format,
+    ) -> Result<Rendered, CompileError> {
+        let world = self.wrap_source(source, origin);
+        match format {
+            Format::Svg | Format::Png => self.render_paged(&world, format),
+            Format::Mathml => self.render_mathml(&world),
+            Format::Html => self.render_html(&world),
+        }
+    }
+
+    /// Compiles a paged document and renders all pages to SVG or PNG.
+    fn render_paged(&self, world: &WrapSource, format: Format) -> Result<Rendered, CompileError> {
+        let Warned { output, warnings } = typst::compile::<PagedDocument>(world);
+
+        match output {
+            Ok(document) => {
+                self.check_pages(world, &warnings, document.pages.len())?;
+
+                let mut images = String::new();
+                let mut pngs = Vec::new();
+                if format == Format::Png {
+                    let mut bytes = 0;
+                    for page in &document.pages {
+                        let pixmap = typst_render::render(page, self.ppi / 72.0);
+                        let data = pixmap.encode_png().map_err(|e| {
+                            CompileError::Unsupported(format!("failed to encode PNG: {}", e))
+                        })?;
+                        // Checked per page to stop rendering early
+                        bytes += data.len();
+                        self.check_size(world, &warnings, "PNG", bytes)?;
+                        pngs.push(PngPage {
+                            data,
+                            width: page.frame.width().to_pt(),
+                            height: page.frame.height().to_pt(),
+                        });
+                    }
+                } else {
+                    images = document
+                        .pages
+                        .iter()
+                        .map(svg)
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    self.check_size(world, &warnings, "SVG", images.len())?;
+                }
 
-        let Warned { output, warnings } = typst::compile::<PagedDocument>(&world);
+                let probe = probe(&document);
+                Ok(Rendered {
+                    output: images,
+                    pngs,
+                    dependencies: world.dependencies(),
+                    warnings: collect_diagnostics(world, &warnings),
+                    baseline: probe.and_then(|(text_size, position)| {
+                        let page = document.pages.get(position.page.get() - 1)?;
+                        Some((page.frame.height() - position.point.y) / text_size)
+                    }),
+                    text_size: probe.map(|(text_size, _)| text_size.to_pt()),
+                })
+            }
+            Err(errors) => Err(compile_error(world, &errors, &warnings)),
+        }
+    }
+
+    /// Compiles an HTML document and converts its first equation to MathML.
+    fn render_mathml(&self, world: &WrapSource) -> Result<Rendered, CompileError> {
+        let Warned { output, warnings } = typst::compile::<HtmlDocument>(world);
+        // Equations are converted by us, and page setup is irrelevant for
+        // them, so the warnings of the HTML export about these are noise
+        let warnings = warnings
+            .into_iter()
+            .filter(|warning| {
+                !warning.message.ends_with("during HTML export")
+                    && !warning
+                        .message
+                        .starts_with("html export is under active development")
+            })
+            .collect::<Vec<_>>();
 
         match output {
             Ok(document) => {
-                print_diagnostics(&world, &warnings, &[])?;
-                let images = document.pages.iter().map(svg).collect::<Vec<_>>();
-                let images = images.join("\n");
-                Ok(images)
+                let equations = document
+                    .introspector
+                    .query(&Selector::Elem(EquationElem::ELEM, None));
+                let Some(equation) = equations.first() else {
+                    return Err(CompileError::Unsupported(String::from(
+                        "no equation to convert to MathML",
+                    )));
+                };
+                let output = mathml::equation(equation).map_err(|construct| {
+                    CompileError::Unsupported(format!("{construct} has no MathML counterpart"))
+                })?;
+                // The HTML target has no pages, the output counts as one
+                self.check_pages(world, &warnings, 1)?;
+                self.check_size(world, &warnings, "MathML", output.len())?;
+
+                Ok(Rendered {
+                    output,
+                    pngs: Vec::new(),
+                    dependencies: world.dependencies(),
+                    warnings: collect_diagnostics(world, &warnings),
+                    baseline: None,
+                    text_size: None,
+                })
             }
-            Err(errors) => {
-                print_diagnostics(&world, &warnings, &errors)?;
-                Err(CompileError::Compilation(
-                    "typst compilation failed".to_string(),
+            Err(errors) => Err(compile_error(world, &errors, &warnings)),
+        }
+    }
+
+    /// Fails if a document has more than [`Compiler::max_pages`] pages.
+    fn check_pages(
+        &self,
+        world: &WrapSource,
+        warnings: &[SourceDiagnostic],
+        pages: usize,
+    ) -> Result<(), CompileError> {
+        match self.max_pages {
+            Some(max_pages) if pages > max_pages => Err(limit_error(
+                world,
+                warnings,
+                format!(
+                    "document has {} pages, more than the limit of {}",
+                    pages, max_pages
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if an output of `bytes` bytes is larger than
+    /// [`Compiler::max_output_bytes`].
+    fn check_size(
+        &self,
+        world: &WrapSource,
+        warnings: &[SourceDiagnostic],
+        kind: &str,
+        bytes: usize,
+    ) -> Result<(), CompileError> {
+        match self.max_output_bytes {
+            Some(max_bytes) if bytes > max_bytes => Err(limit_error(
+                world,
+                warnings,
+                format!(
+                    "rendered {} has {} bytes, more than the limit of {}",
+                    kind, bytes, max_bytes
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Compiles an HTML document and returns the content of its body.
+    fn render_html(&self, world: &WrapSource) -> Result<Rendered, CompileError> {
+        let Warned { output, warnings } = typst::compile::<HtmlDocument>(world);
+        // Content ignored by the HTML export (e.g. shapes) would silently be
+        // missing from the output, so such blocks are treated as unsupported.
+        // Page setup from the preamble is irrelevant for HTML though.
+        let mut ignored = None;
+        let warnings = warnings
+            .into_iter()
+            .filter(|warning| {
+                if warning
+                    .message
+                    .starts_with("html export is under active development")
+                    || warning.message == "page set rule was ignored during HTML export"
+                {
+                    return false;
+                }
+                if warning.message.ends_with("was ignored during HTML export") {
+                    ignored.get_or_insert_with(|| warning.message.to_string());
+                    return false;
+                }
+                true
+            })
+            .collect::<Vec<_>>();
+
+        match output {
+            Ok(document) => {
+                if let Some(ignored) = ignored {
+                    return Err(CompileError::Unsupported(ignored));
+                }
+                let html = typst_html::html(&document)
+                    .map_err(|errors| compile_error(world, &errors, &warnings))?;
+                let body = html
+                    .split_once("<body>")
+                    .and_then(|(_, rest)| rest.rsplit_once("</body>"))
+                    .map_or("", |(body, _)| body)
+                    .trim();
+                // The HTML target has no pages, the output counts as one
+                self.check_pages(world, &warnings, 1)?;
+                self.check_size(world, &warnings, "HTML", body.len())?;
+
+                Ok(Rendered {
+                    output: body.to_string(),
+                    pngs: Vec::new(),
+                    dependencies: world.dependencies(),
+                    warnings: collect_diagnostics(world, &warnings),
+                    baseline: None,
+                    text_size: None,
+                })
+            }
+            Err(errors) => Err(compile_error(world, &errors, &warnings)),
+        }
+    }
+
+    /// Renders Typst source code to the given [`Format`], giving up after
+    /// `timeout`.
+    ///
+    /// Works like [`Compiler::render`], but compiles on a separate thread.
+    /// If compilation does not finish in time, a [`CompileError::Compilation`]
+    /// pointing at the whole source is returned.
+    ///
+    /// Typst compilation cannot be interrupted, so the thread keeps running
+    /// in the background until it finishes or the process exits.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Compiler::render`], plus the timeout error.
+    pub fn render_with_timeout(
+        self: &Arc<Self>,
+        source: impl Into<String>,
+        origin: Origin,
+        format: Format,
+        timeout: Duration,
+    ) -> Result<Rendered, CompileError> {
+        let source = source.into();
+        let (sender, receiver) = mpsc::channel();
+        {
+            let compiler = Arc::clone(self);
+            let source = source.clone();
+            let origin = origin.clone();
+            thread::spawn(move || {
+                // The receiver is gone if the timeout has passed
+                sender.send(compiler.render(source, origin, format)).ok();
+            });
+        }
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                let world = self.wrap_source(source, origin);
+                Err(limit_error(
+                    &world,
+                    &[],
+                    format!("compilation timed out after {:?}", timeout),
                 ))
             }
         }
     }
 }
 
+/// Creates the error for a file outside of the allowed directories.
+fn access_denied(id: FileId) -> FileError {
+    FileError::Other(Some(eco_format!(
+        "access denied: {} resolves outside of the allowed directories",
+        id.vpath().as_rooted_path().display()
+    )))
+}
+
+/// Creates the error for a failed compilation.
+fn compile_error(
+    world: &WrapSource,
+    errors: &[SourceDiagnostic],
+    warnings: &[SourceDiagnostic],
+) -> CompileError {
+    let mut diagnostics = collect_diagnostics(world, errors);
+    diagnostics.extend(collect_diagnostics(world, warnings));
+    CompileError::Compilation(diagnostics)
+}
+
+/// Reads the text size and position recorded by the [`TEXT_SIZE_PROBE`] of
+/// a paged document.
+///
+/// The probe is placed inline after the source, so its position is on the
+/// baseline of the last line. Returns `None` if the document has no probe.
+fn probe(document: &PagedDocument) -> Option<(Abs, Position)> {
+    let label = typst::foundations::Label::new(PicoStr::intern("typst-math-text-size"))?;
+    let probe = document.introspector.query_label(label).ok()?;
+    let Ok(Value::Length(text_size)) = probe.get_by_name("value") else {
+        return None;
+    };
+    let position = document.introspector.position(probe.location()?);
+    (text_size.abs > Abs::zero()).then_some((text_size.abs, position))
+}
+
+/// Creates an error for a source that exceeded a limit, pointing at the
+/// source without the preamble.
+fn limit_error(world: &WrapSource, warnings: &[SourceDiagnostic], message: String) -> CompileError {
+    let len = world.source.text().len();
+    let range = world.origin.preamble_len.min(len)..len;
+    let error = SourceDiagnostic::error(Span::from_range(world.source.id(), range), message);
+    let mut diagnostics = collect_diagnostics(world, &[error]);
+    diagnostics.extend(collect_diagnostics(world, warnings));
+    CompileError::Compilation(diagnostics)
+}
+
 /// A wrapper that provides a complete Typst [`World`] for compilation.
 ///
 /// This struct combines a [`Compiler`] reference with a specific source
@@ -334,17 +870,64 @@ pub struct WrapSource<'a> {
     compiler: &'a Compiler,
     /// The main source document to compile.
     source: Source,
+    /// Where the main source comes from.
+    origin: Origin,
     /// The time to use for date-related Typst functions.
     time: time::OffsetDateTime,
-    /// The line number in the original markdown file where this block starts (1-indexed).
-    markdown_line: usize,
-    /// Number of lines in the preamble before the actual math content.
-    preamble_lines: usize,
+    /// Files accessed through the [`World`] during compilation.
+    accessed: Mutex<HashSet<FileId>>,
 }
 
 impl WrapSource<'_> {
+    /// Returns the paths of all project files accessed so far.
+    ///
+    /// Files from `@preview` packages are skipped since a published package
+    /// version never changes, unlike local packages under development. If
+    /// any package was used, the lockfile is included, so that the packages
+    /// are verified again when it changes.
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        let accessed = self.accessed.lock().unwrap();
+        let mut paths = accessed
+            .iter()
+            .filter_map(|id| match id.package() {
+                None => id.vpath().resolve(&self.compiler.root),
+                Some(package) if package.namespace == "preview" => None,
+                Some(package) => id.vpath().resolve(&self.compiler.package(package).ok()?),
+            })
+            .collect::<Vec<_>>();
+        if let Some(ref lock) = self.compiler.lock {
+            if accessed.iter().any(|id| id.package().is_some()) {
+                paths.push(lock.path().to_path_buf());
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    /// Maps a byte range in a file to the file and range to show in diagnostics.
+    ///
+    /// Ranges in the main source are mapped to the markdown file, or to the
+    /// preamble if they lie within it.
+    pub fn map_range(&self, id: FileId, range: Range<usize>) -> (FileId, Range<usize>) {
+        if id != self.source.id() || self.origin.markdown.is_none() {
+            (id, range)
+        } else if range.start < self.origin.preamble_len {
+            (self.origin.preamble.id(), range)
+        } else {
+            (id, self.origin.map_range(range))
+        }
+    }
+
+    /// Gets the text of a file as shown in diagnostics.
     pub fn lookup(&self, id: FileId) -> Lines<String> {
-        if let Ok(source) = self.compiler.get_source(id) {
+        if id == self.source.id() {
+            match self.origin.markdown {
+                Some(ref markdown) => markdown.clone(),
+                None => self.source.lines().clone(),
+            }
+        } else if PREAMBLE_IDS.contains(&id) {
+            self.source.lines().clone()
+        } else if let Ok(source) = self.compiler.get_source(id) {
             source.lines().clone()
         } else if let Ok(bytes) = self.compiler.get_file(id) {
             Lines::try_from(&bytes).expect("not valid utf-8")
@@ -371,11 +954,13 @@ impl World for WrapSource<'_> {
         if id == self.source.id() {
             Ok(self.source.clone())
         } else {
+            self.accessed.lock().unwrap().insert(id);
             self.compiler.get_source(id)
         }
     }
 
     fn file(&self, id: FileId) -> FileResult<Bytes> {
+        self.accessed.lock().unwrap().insert(id);
         self.compiler.get_file(id)
     }
 
@@ -395,6 +980,10 @@ impl<'a> codespan_reporting::files::Files<'a> for WrapSource<'a> {
     type Source = Lines<String>;
 
     fn name(&'a self, id: Self::FileId) -> Result<Self::Name, codespan_reporting::files::Error> {
+        if let (true, Some(name)) = (id == self.source.id(), &self.origin.name) {
+            return Ok(name.clone());
+        }
+
         let vpath = id.vpath();
         Ok(if let Some(package) = id.package() {
             format!("{package}{}", vpath.as_rooted_path().display())
@@ -415,22 +1004,15 @@ impl<'a> codespan_reporting::files::Files<'a> for WrapSource<'a> {
         id: Self::FileId,
         byte_index: usize,
     ) -> Result<usize, codespan_reporting::files::Error> {
+        // Ranges in the main source have already been mapped to the
+        // markdown file by `label`, so no adjustment is needed here
         let source = self.lookup(id);
-        let typst_line = source.byte_to_line(byte_index).ok_or_else(|| {
+        source.byte_to_line(byte_index).ok_or_else(|| {
             codespan_reporting::files::Error::IndexTooLarge {
                 given: byte_index,
                 max: source.len_bytes(),
             }
-        })?;
-
-        // Adjust line number to point to the original markdown file
-        if id == self.source.id() && typst_line >= self.preamble_lines {
-            // Line is in the actual content (after preamble)
-            // Both markdown_line and returned value are 0-indexed
-            Ok(self.markdown_line - 1 + (typst_line - self.preamble_lines))
-        } else {
-            Ok(typst_line)
-        }
+        })
     }
 
     fn line_range(
@@ -439,16 +1021,7 @@ impl<'a> codespan_reporting::files::Files<'a> for WrapSource<'a> {
         line_index: usize,
     ) -> Result<std::ops::Range<usize>, codespan_reporting::files::Error> {
         let source = self.lookup(id);
-
-        // Convert adjusted markdown line back to Typst line
-        let typst_line = if id == self.source.id() && line_index >= self.markdown_line - 1 {
-            // This is an adjusted line number, convert back to Typst line
-            self.preamble_lines + (line_index - (self.markdown_line - 1))
-        } else {
-            line_index
-        };
-
-        source.line_to_range(typst_line).ok_or_else(|| {
+        source.line_to_range(line_index).ok_or_else(|| {
             codespan_reporting::files::Error::LineTooLarge {
                 given: line_index,
                 max: source.len_lines(),
@@ -462,52 +1035,124 @@ impl<'a> codespan_reporting::files::Files<'a> for WrapSource<'a> {
         _line_index: usize,
         byte_index: usize,
     ) -> Result<usize, codespan_reporting::files::Error> {
+        // Column numbers are 1-indexed
         let source = self.lookup(id);
-        source.byte_to_column(byte_index).ok_or_else(|| {
-            let max = source.len_bytes();
-            if byte_index <= max {
-                codespan_reporting::files::Error::InvalidCharBoundary { given: byte_index }
-            } else {
-                codespan_reporting::files::Error::IndexTooLarge {
-                    given: byte_index,
-                    max,
+        source
+            .byte_to_column(byte_index)
+            .map(|column| column + 1)
+            .ok_or_else(|| {
+                let max = source.len_bytes();
+                if byte_index <= max {
+                    codespan_reporting::files::Error::InvalidCharBoundary { given: byte_index }
+                } else {
+                    codespan_reporting::files::Error::IndexTooLarge {
+                        given: byte_index,
+                        max,
+                    }
                 }
-            }
-        })
+            })
     }
 }
 
 fn label(world: &WrapSource, span: Span) -> Option<Label<FileId>> {
-    Some(Label::primary(span.id()?, world.range(span)?))
+    let (id, range) = world.map_range(span.id()?, world.range(span)?);
+    Some(Label::primary(id, range))
 }
 
-pub fn print_diagnostics(
+/// Converts Typst's diagnostics into [`TypstDiagnostic`]s, resolving their
+/// locations and formatting them for humans.
+pub fn collect_diagnostics(
     world: &WrapSource,
-    warnings: &[SourceDiagnostic],
-    errors: &[SourceDiagnostic],
-) -> Result<(), CompileError> {
-    for diagnostic in warnings.iter().chain(errors) {
-        let diag = match diagnostic.severity {
-            typst::diag::Severity::Error => Diagnostic::error(),
-            typst::diag::Severity::Warning => Diagnostic::warning(),
-        }
-        .with_message(diagnostic.message.clone())
-        .with_notes(
-            diagnostic
-                .hints
-                .iter()
-                .map(|s| (eco_format!("hint: {s}")).into())
-                .collect(),
-        )
-        .with_labels(label(world, diagnostic.span).into_iter().collect());
-
-        let diag = term::emit_into_string(&term::Config::default(), world, &diag)
-            .map_err(|e| CompileError::Compilation(format! {"Failed to format diagnostic: {e}"}))?;
-        match diagnostic.severity {
-            typst::diag::Severity::Error => error!("Typst: {diag}"),
-            typst::diag::Severity::Warning => warn!("Typst: {diag}"),
-        }
+    diagnostics: &[SourceDiagnostic],
+) -> Vec<TypstDiagnostic> {
+    diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let label = label(world, diagnostic.span);
+            let diag = match diagnostic.severity {
+                typst::diag::Severity::Error => Diagnostic::error(),
+                typst::diag::Severity::Warning => Diagnostic::warning(),
+            }
+            .with_message(diagnostic.message.clone())
+            .with_notes(
+                diagnostic
+                    .hints
+                    .iter()
+                    .map(|s| (eco_format!("hint: {s}")).into())
+                    .collect(),
+            )
+            .with_labels(label.clone().into_iter().collect());
+
+            // Fall back to the bare message so that a formatting failure does not
+            // hide the actual diagnostic
+            let rendered = term::emit_into_string(&term::Config::default(), world, &diag)
+                .unwrap_or_else(|e| {
+                    format!("{} (failed to format location: {e})", diagnostic.message)
+                });
+
+            let mut result = TypstDiagnostic {
+                severity: diagnostic.severity.into(),
+                message: diagnostic.message.to_string(),
+                hints: diagnostic.hints.iter().map(ToString::to_string).collect(),
+                file: None,
+                line: None,
+                column: None,
+                snippet: None,
+                rendered,
+            };
+            if let Some(label) = label {
+                let start = label.range.start;
+                result.file = match (label.file_id == world.source.id(), &world.origin.file) {
+                    (true, Some(file)) => Some(file.clone()),
+                    _ => world.name(label.file_id).ok(),
+                };
+                result.line = world.line_index(label.file_id, start).ok();
+                result.column = result
+                    .line
+                    .and_then(|line| world.column_number(label.file_id, line, start).ok());
+                result.line = result.line.map(|line| line + 1);
+                result.snippet = world
+                    .lookup(label.file_id)
+                    .text()
+                    .get(label.range)
+                    .map(String::from);
+            }
+            result
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders an empty block after `preamble`, returning the warnings.
+    fn preamble_warnings(preamble: &str, kind: Preamble) -> Vec<TypstDiagnostic> {
+        let preamble = format!("{preamble}\n");
+        let origin = Origin {
+            markdown: Some(Lines::new(String::new())),
+            preamble_len: preamble.len(),
+            preamble: kind,
+            ..Origin::default()
+        };
+        Compiler::new()
+            .render(preamble, origin, Format::Svg)
+            .unwrap()
+            .warnings
     }
 
-    Ok(())
+    #[test]
+    fn preambles_are_named_apart() {
+        let preamble = "#set text(font: \"no-such-font\")";
+        for (kind, name) in [
+            (Preamble::Shared, "preamble"),
+            (Preamble::Inline, "inline-preamble"),
+            (Preamble::Display, "display-preamble"),
+        ] {
+            let warnings = preamble_warnings(preamble, kind);
+            assert_eq!(warnings.len(), 1, "{warnings:?}");
+            assert_eq!(warnings[0].file.as_deref(), Some(name));
+            assert_eq!(warnings[0].line, Some(1));
+        }
+    }
 }
@@ -19,23 +19,80 @@
 //! - `inline_preamble`: Typst code to prepend to inline math blocks
 //! - `display_preamble`: Typst code to prepend to display math blocks
 //! - `fonts`: List of font directories to load
-//! - `cache`: Directory for caching downloaded packages
+//! - `cache`: Directory for caching downloaded packages and rendered blocks
+//!   (default: the package cache of the Typst CLI, without render cache)
+//! - `package_dirs`: Local directories containing packages, searched before the cache
+//! - `registries`: Base URL or local directory to fetch the packages of each namespace
+//!   from (default: `packages.typst.org` for `preview`)
+//! - `lock`: How the `typst-packages.lock` lockfile is used (`off`, `verify`, `strict` or
+//!   `update`), can be overridden with the `MDBOOK_TYPST_MATH_LOCK` environment variable
+//! - `offline`: Never download packages (default: `false`), can be overridden with
+//!   the `MDBOOK_TYPST_MATH_OFFLINE` environment variable
+//! - `render_cache`: Enable the on-disk cache of rendered blocks (default: `true`)
+//! - `render_cache_size`: Maximum size of the render cache in MiB (default: `256`)
+//! - `root`: Project root for resolving imports, images and data files
+//!   (default: the book's `src` directory)
+//! - `allowed_paths`: Additional directories that files in the project root may
+//!   link to (default: none)
+//! - `output`: Output format for math blocks (`svg` or `mathml`)
+//! - `code_output`: Output format for Typst code blocks (`svg` or `html`)
 //! - `color_mode`: Color mode for SVG output (`auto` or `static`)
+//! - `svg_units`: Units of the SVG width and height (`pt` or `em`)
+//! - `shared_glyphs`: Share glyph definitions between the SVGs of a chapter (default: `false`)
+//! - `embed`: How SVGs are embedded into the chapters (`inline`, `file` or `data-uri`)
+//! - `renderer_output`: How blocks are rendered for each renderer (`svg`, `svg-file`,
+//!   `png`, `mathml` or `source`, default: `html = "svg"`)
+//! - `png_ppi`: Resolution of PNG output in pixels per inch (default: `144`)
+//! - `assets_dir`: Directory in the book's `src` directory for SVG files written
+//!   with `embed = "file"` (default: `typst-assets`)
 //! - `code_tag`: Language tag for code blocks to render as Typst (default: `typst,render`)
 //! - `enable_math`: Enable rendering of math blocks (default: `true`)
 //! - `enable_code`: Enable rendering of Typst code blocks (default: `true`)
+//! - `on_error`: How to handle blocks that fail to compile (`fail`, `placeholder` or `source`)
+//! - `fail_on_error`: Fail the build at the end if any block failed (default: `false`)
+//! - `diagnostics_format`: Format of Typst diagnostics (`human` or `json`), can be
+//!   overridden with the `MDBOOK_TYPST_MATH_DIAGNOSTICS_FORMAT` environment variable
+//! - `warnings`: Whether Typst warnings are allowed or fail the build (`allow` or `deny`)
+//! - `allow_warnings`: Warning messages to suppress, matched as substrings
+//! - `timeout`: Maximum time in seconds to compile a single block (default: no limit)
+//! - `max_pages`: Maximum number of pages a block may produce (default: no limit)
+//! - `max_output_bytes`: Maximum size in bytes of the output of a block, in any
+//!   format (default: no limit)
+//! - `jobs`: Number of worker threads used to render blocks (default: number of CPUs)
 
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use mdbook_preprocessor::book::{Book, BookItem, Chapter};
+use mdbook_preprocessor::config::Config;
 use mdbook_preprocessor::errors::Result;
 use mdbook_preprocessor::{Preprocessor, PreprocessorContext};
-use serde::Deserialize;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
 
+mod cache;
 mod compiler;
-use compiler::{CompileError, Compiler};
+mod lock;
+mod mathml;
+mod package;
+use cache::RenderCache;
+pub use compiler::{CompileError, Severity, TypstDiagnostic};
+use compiler::{Compiler, Format, Origin, PngPage, Preamble, Segment, TEXT_SIZE_PROBE};
+pub use lock::{LockMode, PackageLock, LOCK_FILE};
+pub use package::{
+    typst_package_cache, typst_package_path, HttpRegistry, LocalPackages, LocalRegistry,
+    MemoryPackages, PackageChain, PackageProvider, PREVIEW_URL,
+};
 use typst::foundations::Bytes;
+use typst::syntax::Lines;
 use typst::text::{Font, FontInfo};
 
 /// Options that control how Typst renders math blocks.
@@ -56,18 +113,52 @@ pub struct TypstProcessorOptions {
     ///
     /// If `None`, the default `preamble` is used instead.
     pub display_preamble: Option<String>,
+    /// Output format for math blocks.
+    pub output: MathOutput,
+    /// Output format for Typst code blocks.
+    ///
+    /// Can be overridden per block with a `,svg` or `,html` suffix on the
+    /// code tag, e.g. `typst,render,html`.
+    pub code_output: CodeOutput,
     /// Color mode for SVG output.
     ///
     /// When set to `Auto`, black color (`#000000`) in SVG will be replaced
     /// with `currentColor`, allowing CSS to control the text color for
     /// theme support (light/dark mode).
     pub color_mode: ColorMode,
+    /// Units of the width and height of SVG output.
+    pub svg_units: SvgUnits,
+    /// Move the glyph definitions of all SVGs in a chapter into a single
+    /// hidden SVG, which the formulas reference.
+    pub shared_glyphs: bool,
+    /// How SVGs are embedded into the chapters.
+    pub embed: Embed,
+    /// How blocks are rendered for the renderer the book is processed for.
+    pub renderer_output: RendererOutput,
+    /// Directory for the SVG files written with [`Embed::File`], relative to
+    /// the book's `src` directory. This is the subdirectory of the configured
+    /// directory for the renderer the book is processed for.
+    pub assets_dir: PathBuf,
     /// Language tag for code blocks to render as Typst.
     pub code_tag: String,
     /// Enable rendering of math blocks (inline and display math).
     pub enable_math: bool,
     /// Enable rendering of Typst code blocks.
     pub enable_code: bool,
+    /// How to handle blocks that fail to compile.
+    pub on_error: ErrorMode,
+    /// Fail the build after all chapters are processed if any block failed.
+    ///
+    /// Only relevant if `on_error` is not [`ErrorMode::Fail`].
+    pub fail_on_error: bool,
+    /// Format used to report Typst diagnostics.
+    pub diagnostics_format: DiagnosticsFormat,
+    /// Whether Typst warnings are allowed or fail the build.
+    pub warnings: WarningMode,
+    /// Warnings whose message contains any of these patterns are suppressed.
+    pub allow_warnings: Vec<String>,
+    /// Maximum time to compile a single block, unlimited if `None`.
+    pub timeout: Option<Duration>,
 }
 
 /// Color mode for SVG output.
@@ -89,6 +180,243 @@ pub enum ColorMode {
     Static,
 }
 
+/// Units of the width and height of SVG output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SvgUnits {
+    /// Keep the absolute sizes in pt produced by Typst.
+    ///
+    /// This is the default.
+    #[default]
+    Pt,
+    /// Express sizes in em, relative to the Typst text size.
+    ///
+    /// Formulas scale with the font size of the surrounding text, so text
+    /// set in Typst has the same size as the text of the page.
+    Em,
+}
+
+/// How blocks are rendered for a renderer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RendererOutput {
+    /// Render blocks to SVG images, embedded according to [`Embed`].
+    ///
+    /// This is the default.
+    #[default]
+    Svg,
+    /// Render blocks to SVG images written to files in the assets
+    /// directory, regardless of [`Embed`].
+    #[serde(rename = "svg-file")]
+    SvgFile,
+    /// Render blocks to PNG images, for renderers that cannot display SVG.
+    ///
+    /// The images are written to files in the assets directory, or embedded
+    /// as `data:` URIs with `embed = "data-uri"`.
+    Png,
+    /// Render math blocks to MathML, regardless of `output`. Typst code
+    /// blocks are rendered to SVG images or HTML.
+    Mathml,
+    /// Leave the book untouched, e.g. for renderers that process the
+    /// markdown source.
+    Source,
+}
+
+/// How SVGs are embedded into the chapters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Embed {
+    /// Insert the SVG markup into the chapter.
+    ///
+    /// This is the default. The SVGs inherit the text color of the page.
+    #[default]
+    Inline,
+    /// Write each SVG to a content-hashed file in the assets directory and
+    /// reference it with an `<img>` element.
+    ///
+    /// Browsers can cache the files, and `print.html` reuses them.
+    File,
+    /// Reference each SVG with an `<img>` element with a `data:` URI.
+    ///
+    /// The SVGs are self-contained, so their styles and IDs cannot affect
+    /// the page.
+    #[serde(rename = "data-uri")]
+    DataUri,
+}
+
+/// Output format for math blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MathOutput {
+    /// Render math to SVG images.
+    ///
+    /// This is the default format.
+    #[default]
+    Svg,
+    /// Render math to native MathML markup.
+    ///
+    /// The text of MathML formulas can be read by screen readers, reflows
+    /// when zooming and can be copied. Formulas using constructs without a
+    /// MathML counterpart (e.g. `bold(x)`) fall back to SVG with a warning.
+    Mathml,
+}
+
+/// Output format for Typst code blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeOutput {
+    /// Render code blocks to SVG images.
+    ///
+    /// This is the default format.
+    #[default]
+    Svg,
+    /// Render code blocks to semantic HTML.
+    ///
+    /// The text is selectable, searchable and reflows on small screens.
+    /// Equations are embedded as SVG. Blocks with content the HTML export
+    /// does not support (e.g. shapes) fall back to SVG with a warning.
+    Html,
+}
+
+/// How to handle blocks that fail to compile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorMode {
+    /// Fail the build once all blocks have been attempted.
+    ///
+    /// This is the default mode. All errors are reported before failing.
+    #[default]
+    Fail,
+    /// Replace the failing block with a box showing the error message and
+    /// the location of the block in the markdown file.
+    Placeholder,
+    /// Replace the failing block with its raw source in a `<code>` element.
+    Source,
+}
+
+/// Format used to report Typst diagnostics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticsFormat {
+    /// Log diagnostics with source excerpts, like the Typst CLI.
+    ///
+    /// This is the default format.
+    #[default]
+    Human,
+    /// Write one JSON object per diagnostic to stderr.
+    ///
+    /// Each object contains the chapter's path, the line and column, the
+    /// severity, the message and the hints of the diagnostic, which makes
+    /// it easy to convert into annotations in CI.
+    Json,
+}
+
+impl std::str::FromStr for DiagnosticsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "human" => Ok(DiagnosticsFormat::Human),
+            "json" => Ok(DiagnosticsFormat::Json),
+            _ => Err(format!("unknown diagnostics format: {s}")),
+        }
+    }
+}
+
+/// Whether Typst warnings are allowed or fail the build.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WarningMode {
+    /// Report warnings without failing the build.
+    ///
+    /// This is the default mode.
+    #[default]
+    Allow,
+    /// Fail the build if any warning is reported.
+    Deny,
+}
+
+/// Show rules embedding equations as SVG in blocks rendered to HTML.
+const HTML_EQUATION_RULES: &str =
+    "#show math.equation.where(block: false): it => box(html.frame(it))
+#show math.equation.where(block: true): html.frame";
+
+/// Environment variable overriding the `diagnostics_format` option.
+const DIAGNOSTICS_FORMAT_ENV: &str = "MDBOOK_TYPST_MATH_DIAGNOSTICS_FORMAT";
+
+/// Environment variable overriding the `offline` option.
+const OFFLINE_ENV: &str = "MDBOOK_TYPST_MATH_OFFLINE";
+
+/// Environment variable overriding the `lock` option.
+const LOCK_ENV: &str = "MDBOOK_TYPST_MATH_LOCK";
+
+/// A diagnostic as written in the JSON diagnostics format.
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    /// Name of the chapter the diagnostic was reported for.
+    chapter: &'a str,
+    #[serde(flatten)]
+    diagnostic: &'a TypstDiagnostic,
+    /// Number of blocks the diagnostic was reported for.
+    count: usize,
+}
+
+/// Identifies a warning by its message and location.
+type WarningKey = (String, Option<String>, Option<usize>, Option<usize>);
+
+/// Collects the warnings of all blocks, so that identical warnings (e.g.
+/// from the preamble or a package) are reported only once.
+#[derive(Default)]
+struct WarningCollector {
+    /// Unique warnings in the order they were first seen, with the chapter
+    /// they were first seen in and the number of blocks reporting them.
+    warnings: Vec<(String, TypstDiagnostic, usize)>,
+    /// Index into `warnings` by message and location.
+    seen: HashMap<WarningKey, usize>,
+}
+
+impl WarningCollector {
+    /// Adds the warnings of a block, skipping the allowed ones.
+    fn extend(
+        &mut self,
+        chapter: &ChapterBlocks,
+        warnings: impl IntoIterator<Item = TypstDiagnostic>,
+        opts: &TypstProcessorOptions,
+    ) {
+        let mut block_seen = Vec::new();
+        for warning in warnings {
+            if opts
+                .allow_warnings
+                .iter()
+                .any(|pattern| warning.message.contains(pattern.as_str()))
+            {
+                continue;
+            }
+
+            // Warnings inside a chapter have a unique location, so only those
+            // from shared sources like the preamble are actually merged
+            let key = (
+                warning.message.clone(),
+                warning.file.clone(),
+                warning.line,
+                warning.column,
+            );
+            match self.seen.get(&key) {
+                Some(&index) if !block_seen.contains(&index) => {
+                    self.warnings[index].2 += 1;
+                    block_seen.push(index);
+                }
+                Some(_) => {}
+                None => {
+                    block_seen.push(self.warnings.len());
+                    self.seen.insert(key, self.warnings.len());
+                    self.warnings.push((chapter.name.clone(), warning, 1));
+                }
+            }
+        }
+    }
+}
+
 /// Represents font configuration that accepts either a single string or an array.
 ///
 /// This allows users to specify fonts in `book.toml` as either:
@@ -126,11 +454,76 @@ struct TypstMathConfig {
     /// Custom fonts to load
     fonts: Option<FontsConfig>,
 
-    /// Cache directory for downloaded packages
+    /// Cache directory for downloaded packages and rendered blocks
     cache: Option<String>,
+
+    /// Local directories containing packages, searched before the cache.
+    /// Relative paths are resolved against the book root.
+    package_dirs: Option<Vec<String>>,
+
+    /// Never download packages.
+    /// Defaults to false if not specified.
+    offline: Option<bool>,
+
+    /// Registry of each package namespace, a base URL or a local directory.
+    /// Relative paths are resolved against the book root.
+    registries: Option<HashMap<String, String>>,
+
+    /// How the package lockfile next to `book.toml` is used.
+    #[serde(default)]
+    lock: LockMode,
+
+    /// Enable the on-disk cache of rendered blocks.
+    /// Defaults to true if not specified. Requires `cache` to be set.
+    render_cache: Option<bool>,
+
+    /// Maximum size of the render cache in MiB.
+    /// Defaults to 256 if not specified.
+    render_cache_size: Option<u64>,
+
+    /// Project root for resolving non-package files.
+    /// Defaults to the book's `src` directory if not specified.
+    root: Option<String>,
+
+    /// Additional directories that files in the project root may link to.
+    /// Relative paths are resolved against the book root.
+    allowed_paths: Option<Vec<String>>,
+
+    /// Output format for math blocks.
+    #[serde(default)]
+    output: MathOutput,
+
+    /// Output format for Typst code blocks.
+    #[serde(default)]
+    code_output: CodeOutput,
+
     #[serde(default)]
     color_mode: ColorMode,
 
+    /// Units of the width and height of SVG output.
+    #[serde(default)]
+    svg_units: SvgUnits,
+
+    /// Share glyph definitions between the SVGs of a chapter.
+    /// Defaults to false if not specified.
+    shared_glyphs: Option<bool>,
+
+    /// How SVGs are embedded into the chapters.
+    #[serde(default)]
+    embed: Embed,
+
+    /// How blocks are rendered for each renderer. Only the listed renderers
+    /// are processed. Defaults to SVG for the `html` renderer if not specified.
+    renderer_output: Option<HashMap<String, RendererOutput>>,
+
+    /// Resolution of PNG output in pixels per inch.
+    /// Defaults to 144 if not specified.
+    png_ppi: Option<f32>,
+
+    /// Directory for SVG files written with `embed = "file"`, relative to
+    /// the book's `src` directory. Defaults to "typst-assets" if not specified.
+    assets_dir: Option<String>,
+
     /// Language tag for code blocks to render as Typst.
     /// Defaults to "typst,render" if not specified.
     code_tag: Option<String>,
@@ -142,8 +535,74 @@ struct TypstMathConfig {
     /// Enable rendering of Typst code blocks.
     /// Defaults to true if not specified.
     enable_code: Option<bool>,
+
+    /// How to handle blocks that fail to compile.
+    #[serde(default)]
+    on_error: ErrorMode,
+
+    /// Fail the build at the end if any block failed to compile.
+    /// Defaults to false if not specified.
+    fail_on_error: Option<bool>,
+
+    /// Format used to report Typst diagnostics.
+    #[serde(default)]
+    diagnostics_format: DiagnosticsFormat,
+
+    /// Whether Typst warnings are allowed or fail the build.
+    #[serde(default)]
+    warnings: WarningMode,
+
+    /// Warning messages to suppress, matched as substrings.
+    allow_warnings: Option<Vec<String>>,
+
+    /// Maximum time in seconds to compile a single block.
+    /// Unlimited if not specified.
+    timeout: Option<f64>,
+
+    /// Maximum number of pages a single block may produce.
+    /// Unlimited if not specified.
+    max_pages: Option<usize>,
+
+    /// Maximum size in bytes of the output rendered for a single block.
+    /// Unlimited if not specified.
+    max_output_bytes: Option<usize>,
+
+    /// Number of worker threads used to render blocks.
+    /// Defaults to the number of CPUs if not specified.
+    jobs: Option<usize>,
+}
+
+/// The error returned by [`TypstProcessor`]'s `run` when blocks failed to
+/// render, or when warnings are denied.
+///
+/// Library users can downcast the returned error to inspect the diagnostics:
+///
+/// ```ignore
+/// if let Err(e) = processor.run(&ctx, book) {
+///     if let Some(e) = e.downcast_ref::<RenderError>() {
+///         for diagnostic in &e.diagnostics {
+///             println!("{:?}: {}", diagnostic.file, diagnostic.message);
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RenderError {
+    /// Summary of the failure.
+    message: String,
+    /// The errors of the blocks that failed to render, or the denied
+    /// warnings.
+    pub diagnostics: Vec<TypstDiagnostic>,
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
 }
 
+impl std::error::Error for RenderError {}
+
 /// The main preprocessor that converts math blocks to Typst-rendered SVGs.
 ///
 /// This preprocessor scans markdown content for inline math (`$...$`) and
@@ -156,10 +615,32 @@ struct TypstMathConfig {
 /// use mdbook_typst_math::TypstProcessor;
 /// use mdbook_preprocessor::Preprocessor;
 ///
-/// let processor = TypstProcessor;
+/// let processor = TypstProcessor::new();
 /// assert_eq!(processor.name(), "typst-math");
 /// ```
-pub struct TypstProcessor;
+#[derive(Default)]
+pub struct TypstProcessor {
+    /// Provider of packages, tried before the configured package sources.
+    packages: Option<Arc<dyn PackageProvider>>,
+}
+
+impl TypstProcessor {
+    /// Creates a preprocessor resolving packages as configured in `book.toml`.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolves packages with `provider` first, before the package
+    /// directories and registries configured in `book.toml`.
+    ///
+    /// Use a [`PackageChain`] to supply several providers.
+    #[must_use]
+    pub fn with_package_provider(mut self, provider: impl PackageProvider + 'static) -> Self {
+        self.packages = Some(Arc::new(provider));
+        self
+    }
+}
 
 impl Preprocessor for TypstProcessor {
     fn name(&self) -> &str {
@@ -173,6 +654,20 @@ impl Preprocessor for TypstProcessor {
             .ok()
             .flatten()
             .unwrap_or_default();
+
+        // Without a `renderer_output` table, mdbook only runs the preprocessor
+        // for the renderers listed in `renderers` or the html renderer
+        let renderer_output = match config.renderer_output {
+            Some(ref outputs) => outputs
+                .get(&ctx.renderer)
+                .copied()
+                .unwrap_or(RendererOutput::Source),
+            None => RendererOutput::Svg,
+        };
+        if renderer_output == RendererOutput::Source {
+            return Ok(book);
+        }
+
         let mut compiler = Compiler::new();
 
         // Set options from config
@@ -182,12 +677,44 @@ impl Preprocessor for TypstProcessor {
             }),
             inline_preamble: config.inline_preamble,
             display_preamble: config.display_preamble,
+            output: match renderer_output {
+                RendererOutput::Mathml => MathOutput::Mathml,
+                _ => config.output,
+            },
+            code_output: config.code_output,
             color_mode: config.color_mode,
+            svg_units: config.svg_units,
+            shared_glyphs: config.shared_glyphs.unwrap_or(false),
+            embed: match renderer_output {
+                RendererOutput::SvgFile => Embed::File,
+                _ => config.embed,
+            },
+            renderer_output,
+            // Renderers get their own files, so that they do not remove each
+            // other's files as stale
+            assets_dir: PathBuf::from(config.assets_dir.as_deref().unwrap_or("typst-assets"))
+                .join(&ctx.renderer),
             code_tag: config
                 .code_tag
                 .unwrap_or_else(|| String::from("typst,render")),
             enable_math: config.enable_math.unwrap_or(true),
             enable_code: config.enable_code.unwrap_or(true),
+            on_error: config.on_error,
+            fail_on_error: config.fail_on_error.unwrap_or(false),
+            diagnostics_format: match std::env::var(DIAGNOSTICS_FORMAT_ENV) {
+                Ok(format) => format.parse().unwrap_or_else(|e| {
+                    warn!("Ignoring {}: {}", DIAGNOSTICS_FORMAT_ENV, e);
+                    config.diagnostics_format
+                }),
+                Err(_) => config.diagnostics_format,
+            },
+            warnings: config.warnings,
+            allow_warnings: config.allow_warnings.unwrap_or_default(),
+            timeout: config.timeout.and_then(|secs| {
+                Duration::try_from_secs_f64(secs)
+                    .map_err(|e| warn!("Ignoring invalid timeout {}: {}", secs, e))
+                    .ok()
+            }),
         };
 
         let mut db = fontdb::Database::new();
@@ -255,41 +782,476 @@ impl Preprocessor for TypstProcessor {
             }
         }
 
-        // Set the cache dir
-        if let Some(ref cache) = config.cache {
-            compiler.cache = PathBuf::from(cache);
+        // Look up packages in the local package directories, then like the
+        // Typst CLI, with the configured registries
+        let offline = match std::env::var(OFFLINE_ENV) {
+            Ok(value) => match value.as_str() {
+                "1" | "true" => true,
+                "0" | "false" | "" => false,
+                _ => {
+                    warn!("Ignoring {}: invalid value {:?}", OFFLINE_ENV, value);
+                    config.offline.unwrap_or(false)
+                }
+            },
+            Err(_) => config.offline.unwrap_or(false),
+        };
+        // Relative to the book root, like the render cache
+        let cache = match config.cache {
+            Some(ref cache) => ctx.root.join(cache),
+            None => typst_package_cache().unwrap_or_default(),
+        };
+        // Verify packages against the lockfile next to `book.toml`
+        let lock_mode = match std::env::var(LOCK_ENV) {
+            Ok(mode) => mode.parse().unwrap_or_else(|e| {
+                warn!("Ignoring {}: {}", LOCK_ENV, e);
+                config.lock
+            }),
+            Err(_) => config.lock,
+        };
+        let lock = match lock_mode {
+            LockMode::Off => None,
+            mode => Some(Arc::new(
+                PackageLock::open(ctx.root.join(LOCK_FILE), mode).map_err(|e| anyhow!(e))?,
+            )),
+        };
+        let mut packages = PackageChain::new();
+        if let Some(ref provider) = self.packages {
+            packages.push(Arc::clone(provider));
+        }
+        for dir in config.package_dirs.iter().flatten() {
+            packages.push(LocalPackages::new(ctx.root.join(dir)));
         }
+        if let Some(dir) = typst_package_path() {
+            packages.push(LocalPackages::new(dir));
+        }
+        let mut registries = config.registries.clone().unwrap_or_default();
+        registries
+            .entry(String::from("preview"))
+            .or_insert_with(|| String::from(PREVIEW_URL));
+        for (namespace, location) in registries {
+            if location.starts_with("http://") || location.starts_with("https://") {
+                packages.push(
+                    HttpRegistry::new(namespace, location, &cache)
+                        .offline(offline)
+                        .lock(lock.clone()),
+                );
+            } else {
+                let dir = location.strip_prefix("file://").unwrap_or(&location);
+                packages.push(
+                    LocalRegistry::new(namespace, ctx.root.join(dir), &cache).lock(lock.clone()),
+                );
+            }
+        }
+        compiler.packages = packages;
+        compiler.lock = lock.clone();
 
-        // record if any errors occurred
-        let mut res = None;
+        // Set the project root, relative paths are resolved against the book root
+        let src_dir = ctx.root.join(&ctx.config.book.src);
+        compiler.root = match config.root {
+            Some(ref root) => ctx.root.join(root),
+            None => src_dir.clone(),
+        };
+        compiler.allowed_paths = config
+            .allowed_paths
+            .iter()
+            .flatten()
+            .map(|path| ctx.root.join(path))
+            .collect();
+
+        // Limit the output of a single block
+        compiler.max_pages = config.max_pages;
+        compiler.max_output_bytes = config.max_output_bytes;
+
+        match config.png_ppi {
+            Some(ppi) if ppi.is_finite() && ppi > 0.0 => compiler.ppi = ppi,
+            Some(ppi) => warn!("Ignoring invalid png_ppi {}", ppi),
+            None => {}
+        }
 
+        // Open the render cache inside the cache dir
+        let render_cache = match config.cache {
+            Some(ref cache) if config.render_cache.unwrap_or(true) => {
+                let dir = ctx.root.join(cache).join(".render-cache");
+                let max_size = config.render_cache_size.unwrap_or(256) << 20;
+                RenderCache::open(&dir, &compiler.fonts, max_size)
+                    .map_err(|e| warn!("Failed to open render cache {:?}: {}", dir, e))
+                    .ok()
+            }
+            _ => None,
+        };
+
+        // Shared with the compilation threads of blocks with a timeout
+        let compiler = Arc::new(compiler);
+
+        // Collect the blocks of all chapters first, so that they can be
+        // rendered concurrently
+        let mut chapters = Vec::new();
         book.for_each_mut(|item| {
-            if let Some(Err(_)) = res {
-                return;
+            if let BookItem::Chapter(ref chapter) = *item {
+                chapters.push(self.collect_typst(chapter, &compiler, ctx, &opts));
             }
+        });
 
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.jobs.unwrap_or(0))
+            .build()
+            .map_err(|e| anyhow!("Failed to create worker pool: {}", e))?;
+        let rendered = pool.install(|| {
+            chapters
+                .par_iter()
+                .map(|chapter| {
+                    chapter
+                        .blocks
+                        .par_iter()
+                        .map(|block| {
+                            self.render_block(
+                                block,
+                                chapter,
+                                &compiler,
+                                render_cache.as_ref(),
+                                &src_dir,
+                                &opts,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        });
+
+        if let Some(ref render_cache) = render_cache {
+            if let Err(e) = render_cache.save() {
+                warn!("Failed to save render cache: {}", e);
+            }
+        }
+
+        // Splice the results back in the same order they were collected
+        let mut results = chapters.iter().zip(rendered);
+        // Every block has been attempted, so all errors are reported at once
+        let mut failed_blocks = 0;
+        let mut failed_chapters = 0;
+        let mut errors = Vec::new();
+        let mut warnings = WarningCollector::default();
+        book.for_each_mut(|item| {
             if let BookItem::Chapter(ref mut chapter) = *item {
-                res = Some(self.convert_typst(chapter, &compiler, &opts).map(|c| {
-                    chapter.content = c;
-                }))
+                let Some((blocks, outputs)) = results.next() else {
+                    return;
+                };
+                let mut fragments = Vec::with_capacity(outputs.len());
+                let mut chapter_failed = false;
+                for (block, output) in blocks.blocks.iter().zip(outputs) {
+                    let e = match output {
+                        Ok((fragment, block_warnings)) => {
+                            warnings.extend(blocks, block_warnings, &opts);
+                            fragments.push(fragment);
+                            continue;
+                        }
+                        Err(e) => e,
+                    };
+                    failed_blocks += 1;
+                    chapter_failed = true;
+
+                    // Errors are reported right away, warnings with the others
+                    if let CompileError::Compilation(ref diagnostics) = e {
+                        let (block_errors, block_warnings): (Vec<_>, Vec<_>) = diagnostics
+                            .iter()
+                            .cloned()
+                            .partition(|diagnostic| diagnostic.severity == Severity::Error);
+                        for diagnostic in &block_errors {
+                            report_diagnostic(&blocks.name, diagnostic, 1, &opts);
+                        }
+                        errors.extend(block_errors);
+                        warnings.extend(blocks, block_warnings, &opts);
+                    }
+
+                    let location = blocks.location(block);
+                    error!("Failed to render block at {}: {}", location, e);
+                    fragments.push(match opts.on_error {
+                        ErrorMode::Placeholder => error_placeholder(block, &location, &e),
+                        ErrorMode::Source | ErrorMode::Fail => source_fallback(block),
+                    });
+                }
+                if chapter_failed {
+                    failed_chapters += 1;
+                }
+                // Glyphs cannot be shared with SVGs isolated in `<img>` elements
+                let sprite = match (opts.shared_glyphs, opts.embed) {
+                    (true, Embed::Inline) => share_glyphs(&mut fragments, &blocks.id_prefix(None)),
+                    _ => None,
+                };
+                blocks.namespace_ids(&mut fragments);
+                if opts.embed != Embed::Inline {
+                    blocks.embed_svgs(&mut fragments, &src_dir, &opts);
+                }
+                chapter.content = Self::splice_typst(&chapter.content, blocks, &fragments);
+                if let Some(sprite) = sprite {
+                    chapter.content = format!("{}\n\n{}\n", chapter.content.trim_end(), sprite);
+                }
             }
         });
 
-        res.unwrap_or(Ok(())).map(|_| book)
+        if let Some(ref lock) = lock {
+            if let Err(e) = lock.save() {
+                warn!("Failed to write {}: {}", lock.path().display(), e);
+            }
+            if lock.mode() == LockMode::Strict && lock.mismatches() > 0 {
+                return Err(anyhow!(
+                    "{} package(s) do not match {}",
+                    lock.mismatches(),
+                    LOCK_FILE
+                ));
+            }
+        }
+
+        prune_assets(&src_dir.join(&opts.assets_dir), &book);
+
+        for (chapter, diagnostic, count) in &warnings.warnings {
+            report_diagnostic(chapter, diagnostic, *count, &opts);
+        }
+
+        if failed_blocks > 0 && (opts.on_error == ErrorMode::Fail || opts.fail_on_error) {
+            return Err(RenderError {
+                message: format!(
+                    "Failed to render {} block(s) in {} chapter(s)",
+                    failed_blocks, failed_chapters
+                ),
+                diagnostics: errors,
+            }
+            .into());
+        }
+
+        if !warnings.warnings.is_empty() && opts.warnings == WarningMode::Deny {
+            return Err(RenderError {
+                message: format!(
+                    "{} warning(s) reported with `warnings = \"deny\"`",
+                    warnings.warnings.len()
+                ),
+                diagnostics: warnings
+                    .warnings
+                    .into_iter()
+                    .map(|(_, diagnostic, _)| diagnostic)
+                    .collect(),
+            }
+            .into());
+        }
+
+        Ok(book)
     }
 
     fn supports_renderer(&self, renderer: &str) -> Result<bool> {
-        Ok(renderer == "html")
+        // mdbook asks before passing the book, from the book's root directory.
+        // Without a readable `book.toml`, e.g. when called elsewhere, only
+        // the html renderer is supported.
+        let config = Config::from_disk("book.toml").and_then(|mut config| {
+            config.update_from_env()?;
+            Ok(config)
+        });
+        let Ok(config) = config else {
+            return Ok(renderer == "html");
+        };
+        let outputs: Option<HashMap<String, RendererOutput>> = config
+            .get(&format!("preprocessor.{}.renderer_output", self.name()))
+            .map_err(|e| anyhow!("Invalid `renderer_output`: {}", e))?;
+        Ok(match outputs {
+            Some(outputs) => outputs
+                .get(renderer)
+                .is_some_and(|output| *output != RendererOutput::Source),
+            None => renderer == "html",
+        })
+    }
+}
+
+/// A Typst block found in a chapter, waiting to be rendered.
+struct TypstBlock {
+    /// Byte range of the block in the chapter's markdown.
+    span: Range<usize>,
+    /// The full Typst source, including the preamble.
+    source: String,
+    /// Whether the block is inline math.
+    inline: bool,
+    /// The format to render the block to.
+    format: Format,
+    /// Length of the preamble in bytes, including the trailing newline.
+    preamble_len: usize,
+    /// Ranges of the Typst source copied verbatim from the markdown.
+    segments: Vec<Segment>,
+    /// The line number in the original markdown file (1-indexed).
+    markdown_line: usize,
+    /// The block's content as written in the markdown, without the preamble.
+    raw: String,
+}
+
+/// The Typst blocks of a chapter together with the chapter's context.
+struct ChapterBlocks {
+    /// Name of the chapter to use in diagnostics.
+    filename: String,
+    /// The chapter's markdown content.
+    markdown: Lines<String>,
+    /// The chapter's source file, relative to the book's `src` directory.
+    source_path: Option<PathBuf>,
+    /// The chapter's name, reported with JSON diagnostics.
+    name: String,
+    /// Path of the chapter within the project root.
+    path: Option<PathBuf>,
+    /// Blocks in the order they appear in the chapter.
+    blocks: Vec<TypstBlock>,
+}
+
+impl ChapterBlocks {
+    /// Prefixes the IDs defined in the SVGs of each fragment with a hash of
+    /// the block, so that they cannot collide with the IDs of other blocks,
+    /// even when all chapters are on one page.
+    ///
+    /// The hash depends on the chapter, the block's source and how often the
+    /// same source occurred before in the chapter, so it is stable between
+    /// builds and when unrelated blocks are edited.
+    fn namespace_ids(&self, fragments: &mut [String]) {
+        let mut occurrences = HashMap::new();
+        for (block, fragment) in self.blocks.iter().zip(fragments) {
+            let occurrence = occurrences.entry(block.source.as_str()).or_insert(0usize);
+            let prefix = self.id_prefix(Some((&block.source, *occurrence)));
+            *occurrence += 1;
+            *fragment = namespace_svg_ids(fragment, &prefix);
+        }
+    }
+
+    /// Replaces the SVG documents in each fragment with `<img>` elements
+    /// referencing a file in the assets directory or a `data:` URI.
+    ///
+    /// SVGs whose file cannot be written are left inline.
+    fn embed_svgs(&self, fragments: &mut [String], src_dir: &Path, opts: &TypstProcessorOptions) {
+        const SVG_START: &str = "<svg class=\"typst-doc\"";
+        const SVG_END: &str = "</svg>";
+
+        let assets_url = self.assets_url(opts);
+        for (block, fragment) in self.blocks.iter().zip(fragments) {
+            let alt = image_alt(block);
+            let mut result = String::with_capacity(fragment.len());
+            let mut rest = fragment.as_str();
+            while let Some(start) = rest.find(SVG_START) {
+                let Some(end) = rest[start..]
+                    .find(SVG_END)
+                    .map(|end| start + end + SVG_END.len())
+                else {
+                    break;
+                };
+                result.push_str(&rest[..start]);
+                let svg = &rest[start..end];
+                rest = &rest[end..];
+
+                let src = match opts.embed {
+                    Embed::File => {
+                        match write_asset(&src_dir.join(&opts.assets_dir), svg.as_bytes(), "svg") {
+                            Some((name, _)) => format!("{}/{}", assets_url, name),
+                            None => {
+                                result.push_str(svg);
+                                continue;
+                            }
+                        }
+                    }
+                    _ => format!("data:image/svg+xml;base64,{}", BASE64.encode(svg)),
+                };
+                result.push_str(&svg_img(svg, &src, &alt));
+            }
+            result.push_str(rest);
+            *fragment = result;
+        }
+    }
+
+    /// Creates `<img>` elements showing the PNG pages of a block.
+    ///
+    /// PNGs are written to files in the assets directory, unless `embed` is
+    /// [`Embed::DataUri`] or the file cannot be written. Returns the elements
+    /// and the paths of the written files.
+    fn png_images(
+        &self,
+        block: &TypstBlock,
+        pngs: &[PngPage],
+        text_size: Option<f64>,
+        src_dir: &Path,
+        opts: &TypstProcessorOptions,
+    ) -> (String, Vec<PathBuf>) {
+        let alt = image_alt(block);
+        let mut files = Vec::new();
+        let images = pngs
+            .iter()
+            .map(|page| {
+                let file = match opts.embed {
+                    Embed::DataUri => None,
+                    _ => write_asset(&src_dir.join(&opts.assets_dir), &page.data, "png"),
+                };
+                let src = match file {
+                    Some((name, path)) => {
+                        files.push(path);
+                        format!("{}/{}", self.assets_url(opts), name)
+                    }
+                    None => format!("data:image/png;base64,{}", BASE64.encode(&page.data)),
+                };
+                let css_size = match (opts.svg_units, text_size) {
+                    (SvgUnits::Em, Some(text_size)) => Some((
+                        format!("{:.4}em", page.width / text_size),
+                        format!("{:.4}em", page.height / text_size),
+                    )),
+                    _ => None,
+                };
+                img_element(&src, &alt, Some((page.width, page.height)), css_size)
+            })
+            .collect::<Vec<_>>();
+        (images.join("\n"), files)
+    }
+
+    /// Returns the URL of the assets directory relative to the chapter's
+    /// HTML page.
+    fn assets_url(&self, opts: &TypstProcessorOptions) -> String {
+        let depth = self
+            .source_path
+            .as_ref()
+            .and_then(|path| path.parent())
+            .map_or(0, |parent| parent.components().count());
+        std::iter::repeat_n("..", depth)
+            .chain(
+                opts.assets_dir
+                    .iter()
+                    .map(|part| part.to_str().unwrap_or_default()),
+            )
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Returns an ID prefix for the chapter, or for the `occurrence`-th block
+    /// of the chapter with the given source.
+    fn id_prefix(&self, block: Option<(&str, usize)>) -> String {
+        let mut hasher = Sha256::new();
+        match self.source_path {
+            Some(ref path) => hasher.update(path.to_string_lossy().as_bytes()),
+            None => hasher.update(self.filename.as_bytes()),
+        }
+        if let Some((source, occurrence)) = block {
+            hasher.update([0]);
+            hasher.update(source.as_bytes());
+            hasher.update(occurrence.to_le_bytes());
+        }
+        let hash = format!("{:x}", hasher.finalize());
+        format!("typst-{}-", &hash[..8])
+    }
+
+    /// Returns the markdown location of a block, e.g. `chapter/intro.md:12`.
+    fn location(&self, block: &TypstBlock) -> String {
+        match self.source_path {
+            Some(ref path) => format!("{}:{}", path.display(), block.markdown_line),
+            None => format!("{}:{}", self.filename, block.markdown_line),
+        }
     }
 }
 
 impl TypstProcessor {
-    fn convert_typst(
+    /// Collects all Typst blocks of a chapter without rendering them.
+    fn collect_typst(
         &self,
         chapter: &Chapter,
         compiler: &Compiler,
+        ctx: &PreprocessorContext,
         opts: &TypstProcessorOptions,
-    ) -> Result<String> {
+    ) -> ChapterBlocks {
         use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
 
         // Construct filename from chapter name and source path
@@ -298,7 +1260,21 @@ impl TypstProcessor {
         } else {
             chapter.name.clone()
         };
+        // Path of the chapter within the project root, so that relative file
+        // accesses resolve next to the chapter's markdown file
+        let src_dir = ctx.root.join(&ctx.config.book.src);
+        let path = chapter.source_path.as_ref().map(|path| {
+            let full_path = src_dir.join(path);
+            full_path
+                .strip_prefix(&compiler.root)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| path.clone())
+        });
         let mut typst_blocks = Vec::new();
+        let math_format = match opts.output {
+            MathOutput::Svg => opts.image_format(),
+            MathOutput::Mathml => Format::Mathml,
+        };
 
         let mut pulldown_cmark_opts = Options::empty();
         pulldown_cmark_opts.insert(Options::ENABLE_TABLES);
@@ -308,51 +1284,126 @@ impl TypstProcessor {
         pulldown_cmark_opts.insert(Options::ENABLE_MATH);
 
         let mut in_typst_code_block = false;
-        let mut code_block_start: Option<std::ops::Range<usize>> = None;
+        let mut code_block_format = Format::Svg;
+        let mut code_block_start: Option<Range<usize>> = None;
         let mut code_block_content = String::new();
+        // Pieces of the code block content copied verbatim from the markdown,
+        // as (offset in content, offset in markdown, length)
+        let mut code_block_pieces = Vec::new();
 
-        let parser = Parser::new_ext(&chapter.content, pulldown_cmark_opts);
+        let markdown = chapter.content.as_str();
+        let parser = Parser::new_ext(markdown, pulldown_cmark_opts);
         for (e, span) in parser.into_offset_iter() {
             match e {
                 Event::InlineMath(math_content) if opts.enable_math => {
                     let preamble = opts.inline_preamble.as_ref().unwrap_or(&opts.preamble);
+                    let preamble_len = preamble.len() + 1;
+                    let math = format!("${math_content}$");
+                    // The probe lets the compiler express the baseline in em
+                    let source = format!("{}\n{}{}", preamble, math, TEXT_SIZE_PROBE);
+                    // The `$` delimiters are kept, so the whole block is copied verbatim
+                    let segments = verbatim_segment(markdown, &span, &math)
+                        .map(|(start, len)| Segment {
+                            typst: preamble_len..preamble_len + len,
+                            markdown: start,
+                        })
+                        .into_iter()
+                        .collect();
                     typst_blocks.push((
                         span.clone(),
-                        format!("{}\n${math_content}$", preamble),
+                        source,
                         true,
-                        preamble.lines().count(), // preamble line count
+                        math_format,
+                        preamble_len,
+                        segments,
+                        math_content.to_string(),
                     ));
                 }
                 Event::DisplayMath(math_content) if opts.enable_math => {
                     let math_content = math_content.trim();
                     let preamble = opts.display_preamble.as_ref().unwrap_or(&opts.preamble);
+                    let preamble_len = preamble.len() + 1;
+                    let math = format!("$ {math_content} $");
+                    let source = format!("{}\n{}{}", preamble, math, TEXT_SIZE_PROBE);
+                    // Map the delimiters to the markdown's `$$` and skip the padding
+                    let content_start = preamble_len + "$ ".len();
+                    let mut segments = vec![Segment {
+                        typst: preamble_len..preamble_len + 1,
+                        markdown: span.start,
+                    }];
+                    if let Some((start, len)) = verbatim_segment(markdown, &span, math_content) {
+                        segments.push(Segment {
+                            typst: content_start..content_start + len,
+                            markdown: start,
+                        });
+                    }
+                    let content_end = preamble_len + math.len();
+                    segments.push(Segment {
+                        typst: content_end - 1..content_end,
+                        markdown: span.end - 1,
+                    });
                     typst_blocks.push((
                         span.clone(),
-                        format!("{}\n$ {math_content} $", preamble),
+                        source,
                         false,
-                        preamble.lines().count(), // preamble line count
+                        math_format,
+                        preamble_len,
+                        segments,
+                        math_content.to_string(),
                     ));
                 }
-                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang))) if opts.enable_code => {
-                    if lang.as_ref() == opts.code_tag.as_str() {
-                        in_typst_code_block = true;
-                        code_block_start = Some(span.clone());
-                        code_block_content.clear();
-                    }
+                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang)))
+                    if opts.enable_code && code_format(&lang, opts).is_some() =>
+                {
+                    in_typst_code_block = true;
+                    code_block_format = code_format(&lang, opts).unwrap_or_default();
+                    code_block_start = Some(span.clone());
+                    code_block_content.clear();
+                    code_block_pieces.clear();
                 }
                 Event::Text(text) if in_typst_code_block && opts.enable_code => {
+                    // Text inside indented containers has the indentation
+                    // stripped, so only map text that is unchanged
+                    if markdown.get(span.clone()) == Some(text.as_ref()) {
+                        code_block_pieces.push((code_block_content.len(), span.start, text.len()));
+                    }
                     code_block_content.push_str(&text);
                 }
                 Event::End(TagEnd::CodeBlock) if in_typst_code_block && opts.enable_code => {
                     if let Some(start_span) = code_block_start.take() {
                         let preamble = opts.display_preamble.as_ref().unwrap_or(&opts.preamble);
+                        // Embed equations as SVG, since the HTML export skips them
+                        let preamble = match code_block_format {
+                            Format::Html => format!("{}\n{}", preamble, HTML_EQUATION_RULES),
+                            _ => preamble.clone(),
+                        };
+                        let preamble_len = preamble.len() + 1;
                         let full_span = start_span.start..span.end;
 
+                        // Account for the whitespace removed by trimming
+                        let trimmed = code_block_content.trim();
+                        let lead = code_block_content.len() - code_block_content.trim_start().len();
+                        let segments = code_block_pieces
+                            .iter()
+                            .filter_map(|&(offset, md_start, len)| {
+                                let start = offset.max(lead);
+                                let end = (offset + len).min(lead + trimmed.len());
+                                (start < end).then(|| Segment {
+                                    typst: preamble_len + start - lead..preamble_len + end - lead,
+                                    markdown: md_start + start - offset,
+                                })
+                            })
+                            .collect();
+
                         typst_blocks.push((
                             full_span,
-                            format!("{}\n{}", preamble, code_block_content.trim()),
+                            // On its own line, in case the block ends with a comment
+                            format!("{}\n{}\n{}", preamble, trimmed, TEXT_SIZE_PROBE),
                             false, // Display mode
-                            preamble.lines().count(),
+                            code_block_format,
+                            preamble_len,
+                            segments,
+                            trimmed.to_string(),
                         ));
                     }
                     in_typst_code_block = false;
@@ -362,44 +1413,782 @@ impl TypstProcessor {
             }
         }
 
-        let mut content = chapter.content.to_string();
+        let blocks = typst_blocks
+            .into_iter()
+            .map(
+                |(span, source, inline, format, preamble_len, segments, raw)| {
+                    // Calculate the line number in the original markdown
+                    let markdown_line = markdown[..span.start].matches('\n').count() + 1;
+                    TypstBlock {
+                        span,
+                        source,
+                        inline,
+                        format,
+                        preamble_len,
+                        segments,
+                        markdown_line,
+                        raw,
+                    }
+                },
+            )
+            .collect();
 
-        for (span, block, inline, preamble_lines) in typst_blocks.iter().rev() {
-            let pre_content = &content[0..span.start];
-            let post_content = &content[span.end..];
+        ChapterBlocks {
+            filename,
+            markdown: Lines::new(chapter.content.clone()),
+            source_path: chapter.source_path.clone(),
+            name: chapter.name.clone(),
+            path,
+            blocks,
+        }
+    }
 
-            // Calculate the line number in the original markdown
-            let markdown_line = chapter.content[..span.start].lines().count() + 1;
+    /// Renders a single block to its HTML fragment, reusing the render cache
+    /// if possible.
+    ///
+    /// Returns the fragment together with the warnings reported by Typst.
+    /// Blocks that cannot be expressed in their format are rendered to SVG
+    /// instead.
+    fn render_block(
+        &self,
+        block: &TypstBlock,
+        chapter: &ChapterBlocks,
+        compiler: &Arc<Compiler>,
+        render_cache: Option<&RenderCache>,
+        src_dir: &Path,
+        opts: &TypstProcessorOptions,
+    ) -> std::result::Result<(String, Vec<TypstDiagnostic>), CompileError> {
+        // The chapter path affects how relative imports are resolved
+        let path_key = chapter
+            .path
+            .as_deref()
+            .unwrap_or(Path::new(""))
+            .to_string_lossy();
+        let color_mode_key = format!(
+            "{:?}/{:?}/{:?}/{}",
+            opts.color_mode, opts.svg_units, block.format, block.inline
+        );
+        // PNGs are embedded when they are rendered
+        let png_key = format!("{:?}/{}/{:?}", opts.embed, compiler.ppi, opts.assets_dir);
+        // The root and allowed paths decide which files a block can read
+        let paths_key = format!("{:?}/{:?}", compiler.root, compiler.allowed_paths);
+        // The limits decide whether a block renders at all
+        let limits_key = format!("{:?}/{:?}", compiler.max_pages, compiler.max_output_bytes);
+        // Blocks rendered with packages that failed verification are only
+        // cached when mismatches are allowed
+        let lock_key = format!("{:?}", compiler.lock.as_ref().map(|lock| lock.mode()));
+        let key = RenderCache::key(&[
+            &block.source,
+            &path_key,
+            &paths_key,
+            &color_mode_key,
+            &limits_key,
+            &png_key,
+            &lock_key,
+        ]);
 
-            let mut svg = compiler
-                .render(
-                    block.clone(),
-                    Some(&filename),
-                    markdown_line,
-                    *preamble_lines,
-                )
-                .map_err(|e: CompileError| {
-                    anyhow!("Failed to render math in chapter '{}': {}", filename, e)
-                })?;
+        // Regenerating the lockfile needs every package to be resolved
+        let render_cache_hit = match compiler.lock {
+            Some(ref lock) if lock.mode() == LockMode::Update => None,
+            _ => render_cache.and_then(|cache| cache.get(&key)),
+        };
+        if let Some(fragment) = render_cache_hit {
+            return Ok((fragment, Vec::new()));
+        }
 
-            // Apply color mode transformation
-            if opts.color_mode == ColorMode::Auto {
-                svg = svg.replace(r##"fill="#000000""##, r#"fill="currentColor""#);
-                svg = svg.replace(r##"stroke="#000000""##, r#"stroke="currentColor""#);
+        let origin = Origin {
+            name: Some(chapter.filename.clone()),
+            file: chapter
+                .source_path
+                .as_ref()
+                .map(|path| path.display().to_string()),
+            path: chapter.path.clone(),
+            markdown: Some(chapter.markdown.clone()),
+            span: block.span.clone(),
+            preamble_len: block.preamble_len,
+            preamble: opts.preamble(block.inline),
+            segments: block.segments.clone(),
+        };
+        let render = |format| match opts.timeout {
+            Some(timeout) => {
+                compiler.render_with_timeout(block.source.clone(), origin.clone(), format, timeout)
+            }
+            None => compiler.render(block.source.clone(), origin.clone(), format),
+        };
+        let fallback = match block.format == opts.image_format() {
+            true => Format::Svg,
+            false => opts.image_format(),
+        };
+        let (rendered, format) = match render(block.format) {
+            Err(CompileError::Unsupported(construct)) if block.format != Format::Svg => {
+                warn!(
+                    "Falling back to {} for block at {}: {}",
+                    match fallback {
+                        Format::Png => "PNG",
+                        _ => "SVG",
+                    },
+                    chapter.location(block),
+                    construct
+                );
+                (render(fallback)?, fallback)
             }
+            rendered => (rendered?, block.format),
+        };
+        let mut output = rendered.output;
+        let mut dependencies = rendered.dependencies;
+        if format == Format::Png {
+            let (images, files) =
+                chapter.png_images(block, &rendered.pngs, rendered.text_size, src_dir, opts);
+            output = images;
+            // A deleted image invalidates the cached fragment
+            dependencies.extend(files);
+        }
 
-            content = match inline {
-                true => format!(
-                    "{}<span class=\"typst-inline\">{}</span>{}",
-                    pre_content, svg, post_content
-                ),
-                false => format!(
-                    "{}<div class=\"typst-display\">{}</div>{}",
-                    pre_content, svg, post_content
-                ),
+        // Apply color mode transformation
+        if opts.color_mode == ColorMode::Auto {
+            output = output.replace(r##"fill="#000000""##, r#"fill="currentColor""#);
+            output = output.replace(r##"stroke="#000000""##, r#"stroke="currentColor""#);
+        }
+        if let (SvgUnits::Em, Format::Svg, Some(text_size)) =
+            (opts.svg_units, format, rendered.text_size)
+        {
+            output = svg_size_to_em(&output, text_size);
+        }
+        let shift = baseline_shift(
+            rendered.baseline,
+            rendered.text_size,
+            format,
+            opts.svg_units,
+        );
+        let fragment = wrap_output(block, format, &output, shift.as_deref());
+
+        // Blocks with warnings are not cached, so that the warnings are
+        // reported again on the next build
+        if let (Some(cache), true) = (render_cache, rendered.warnings.is_empty()) {
+            cache.insert(&key, cache_extension(format), &fragment, &dependencies);
+        }
+        Ok((fragment, rendered.warnings))
+    }
+
+    /// Replaces the blocks in `content` with their HTML fragments.
+    fn splice_typst(content: &str, chapter: &ChapterBlocks, fragments: &[String]) -> String {
+        let mut content = content.to_string();
+
+        for (block, fragment) in chapter.blocks.iter().zip(fragments).rev() {
+            let pre_content = &content[0..block.span.start];
+            let post_content = &content[block.span.end..];
+
+            content = format!("{}{}{}", pre_content, fragment, post_content);
+        }
+
+        content
+    }
+}
+
+/// Finds `text` inside the markdown `span`, returning its markdown offset and length.
+fn verbatim_segment(markdown: &str, span: &Range<usize>, text: &str) -> Option<(usize, usize)> {
+    let offset = markdown.get(span.clone())?.find(text)?;
+    Some((span.start + offset, text.len()))
+}
+
+/// Reports a diagnostic of a chapter in the configured format.
+///
+/// `count` is the number of blocks the diagnostic was reported for.
+fn report_diagnostic(
+    chapter: &str,
+    diagnostic: &TypstDiagnostic,
+    count: usize,
+    opts: &TypstProcessorOptions,
+) {
+    match opts.diagnostics_format {
+        DiagnosticsFormat::Human => {
+            let mut rendered = diagnostic.rendered.trim_end().to_string();
+            if count > 1 {
+                rendered = format!("{}\n(reported for {} blocks)", rendered, count);
+            }
+            match diagnostic.severity {
+                Severity::Error => error!("Typst: {}", rendered),
+                Severity::Warning => warn!("Typst: {}", rendered),
+            }
+        }
+        DiagnosticsFormat::Json => {
+            let json = JsonDiagnostic {
+                chapter,
+                diagnostic,
+                count,
             };
+            match serde_json::to_string(&json) {
+                Ok(line) => eprintln!("{}", line),
+                Err(e) => error!("Failed to serialize diagnostic: {}", e),
+            }
         }
+    }
+}
+
+/// Returns the extension of the render cache entries of blocks rendered to
+/// `format`.
+fn cache_extension(format: Format) -> &'static str {
+    match format {
+        Format::Svg => "svg",
+        Format::Mathml => "mathml",
+        // PNG images are stored as files, the fragment holds `<img>` elements
+        Format::Html | Format::Png => "html",
+    }
+}
+
+/// Wraps a rendered output in the element matching the block's type and format.
+///
+/// Inline outputs with a known baseline `shift` (see [`baseline_shift`]) are
+/// shifted down so that their baseline lines up with the surrounding text.
+fn wrap_output(block: &TypstBlock, format: Format, output: &str, shift: Option<&str>) -> String {
+    match (format, block.inline) {
+        (Format::Html, _) => {
+            let mut rules = Vec::new();
+            let html = escape_blank_lines(&styles_to_classes(output, &mut rules));
+            let style = match rules.is_empty() {
+                true => String::new(),
+                false => format!("<style>{}</style>", rules.join(" ")),
+            };
+            format!("<div class=\"typst-html\">{}{}</div>", style, html)
+        }
+        (_, true) => match shift {
+            Some(shift) => format!(
+                "<span class=\"typst-inline\" style=\"vertical-align: {}\">{}</span>",
+                shift, output
+            ),
+            None => format!("<span class=\"typst-inline\">{}</span>", output),
+        },
+        (_, false) => format!("<div class=\"typst-display\">{}</div>", output),
+    }
+}
+
+/// Returns the CSS `vertical-align` offset lining up the baseline of an output
+/// with the surrounding text, in the units the output is sized in.
+///
+/// `baseline` is in units of the `text_size` in pt (see
+/// [`compiler::Rendered::baseline`]). Outputs sized in em are shifted in em, PNG images
+/// in CSS pixels and SVGs in pt.
+fn baseline_shift(
+    baseline: Option<f64>,
+    text_size: Option<f64>,
+    format: Format,
+    units: SvgUnits,
+) -> Option<String> {
+    let baseline = baseline?;
+    match (units, format) {
+        (SvgUnits::Em, _) => Some(format!("{:.4}em", -baseline)),
+        // 1pt = 4/3 CSS pixels, as in the size of the image
+        (SvgUnits::Pt, Format::Png) => Some(format!("{:.4}px", -baseline * text_size? * 4.0 / 3.0)),
+        (SvgUnits::Pt, _) => Some(format!("{:.4}pt", -baseline * text_size?)),
+    }
+}
+
+/// Creates an `<img>` element showing `svg` from `src`.
+///
+/// The size is taken from the SVG's view box. Sizes in em (see
+/// [`SvgUnits::Em`]) are kept as a style.
+fn svg_img(svg: &str, src: &str, alt: &str) -> String {
+    let tag = &svg[..svg.find('>').unwrap_or(svg.len())];
+    let attribute = |name: &str| {
+        let prefix = format!(" {}=\"", name);
+        let start = tag.find(&prefix)? + prefix.len();
+        tag[start..].split('"').next()
+    };
+
+    let view_box = attribute("viewBox").map(|view_box| {
+        view_box
+            .split(' ')
+            .filter_map(|n| n.parse::<f64>().ok())
+            .collect::<Vec<_>>()
+    });
+    let size = match view_box.as_deref() {
+        Some(&[_, _, width, height]) => Some((width, height)),
+        _ => None,
+    };
+    let css_size = match (attribute("width"), attribute("height")) {
+        (Some(width), Some(height)) if width.ends_with("em") => {
+            Some((width.to_string(), height.to_string()))
+        }
+        _ => None,
+    };
+    img_element(src, alt, size, css_size)
+}
+
+/// Creates an `<img>` element of class `typst-doc`.
+///
+/// The `width` and `height` attributes are set from `size` in pt, converted
+/// to CSS pixels, so that browsers reserve the space before loading the
+/// image. `css_size` overrides the displayed size.
+fn img_element(
+    src: &str,
+    alt: &str,
+    size: Option<(f64, f64)>,
+    css_size: Option<(String, String)>,
+) -> String {
+    let mut img = format!(
+        "<img class=\"typst-doc\" src=\"{}\" alt=\"{}\" loading=\"lazy\"",
+        src, alt
+    );
+    if let Some((width, height)) = size {
+        // 1pt = 4/3 CSS pixels
+        img.push_str(&format!(
+            " width=\"{}\" height=\"{}\"",
+            (width * 4.0 / 3.0).round(),
+            (height * 4.0 / 3.0).round()
+        ));
+    }
+    if let Some((width, height)) = css_size {
+        img.push_str(&format!(" style=\"width: {}; height: {}\"", width, height));
+    }
+    img.push('>');
+    img
+}
+
+/// Returns the alt text of the images of a block: its source on one line.
+fn image_alt(block: &TypstBlock) -> String {
+    escape_html(&block.raw.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Writes `data` to a content-hashed file with the given extension in `dir`,
+/// returning the file name and path, or `None` if it cannot be written.
+fn write_asset(dir: &Path, data: &[u8], extension: &str) -> Option<(String, PathBuf)> {
+    let hash = format!("{:x}", Sha256::digest(data));
+    let name = format!("{}.{}", &hash[..16], extension);
+    let path = dir.join(&name);
+    // Unchanged files are not rewritten, so that `mdbook serve` does not
+    // rebuild the book over and over
+    if !path.exists() {
+        fs::create_dir_all(dir)
+            .and_then(|_| fs::write(&path, data))
+            .map_err(|e| warn!("Failed to write {}: {}", path.display(), e))
+            .ok()?;
+    }
+    Some((name, path))
+}
+
+/// Removes the files written by [`write_asset`] to `dir` that no chapter of
+/// `book` refers to anymore, e.g. the images of changed blocks.
+fn prune_assets(dir: &Path, book: &Book) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let contents = book
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Other files may have been put there by hand
+        let is_asset = match name.split_once('.') {
+            Some((hash, "svg" | "png")) => {
+                hash.len() == 16 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            }
+            _ => false,
+        };
+        if is_asset && !contents.iter().any(|content| content.contains(&name)) {
+            if let Err(e) = fs::remove_file(entry.path()) {
+                warn!("Failed to remove {}: {}", entry.path().display(), e);
+            }
+        }
+    }
+}
+
+/// Prefixes the IDs defined inside the `<svg>` elements of `fragment`, and
+/// the references to them, with `prefix`.
+///
+/// IDs that are referenced but not defined in an SVG, like glyphs moved to a
+/// shared definition by [`share_glyphs`], are left unchanged.
+fn namespace_svg_ids(fragment: &str, prefix: &str) -> String {
+    let mut result = String::with_capacity(fragment.len());
+    let mut rest = fragment;
+    while let Some(start) = rest.find("<svg ") {
+        let end = rest[start..]
+            .find("</svg>")
+            .map_or(rest.len(), |end| start + end);
+        result.push_str(&rest[..start]);
+
+        let mut svg = rest[start..end].to_string();
+        let ids = svg
+            .split(" id=\"")
+            .skip(1)
+            .filter_map(|part| part.split('"').next())
+            .map(str::to_string)
+            .collect::<BTreeSet<_>>();
+        for id in ids {
+            let new_id = format!("{}{}", prefix, id);
+            svg = svg
+                .replace(&format!(" id=\"{}\"", id), &format!(" id=\"{}\"", new_id))
+                .replace(&format!("\"#{}\"", id), &format!("\"#{}\"", new_id))
+                .replace(&format!("url(#{})", id), &format!("url(#{})", new_id));
+        }
+        result.push_str(&svg);
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Moves the glyph definitions out of the SVGs in `fragments`, returning a
+/// hidden SVG defining each glyph once, or `None` if there are no glyphs.
+///
+/// Typst names glyphs by a hash of their outline, so a glyph has the same ID
+/// in all SVGs. The IDs are prefixed with the chapter's `prefix`, so that the
+/// definitions of different chapters do not collide in `print.html`.
+fn share_glyphs(fragments: &mut [String], prefix: &str) -> Option<String> {
+    const DEFS_START: &str = "<defs id=\"glyph\">";
+    const DEFS_END: &str = "</defs>";
+
+    let mut symbols = Vec::new();
+    let mut seen = HashSet::new();
+    for fragment in fragments.iter_mut() {
+        let mut ids = Vec::new();
+        while let Some(start) = fragment.find(DEFS_START) {
+            let Some(end) = fragment[start..]
+                .find(DEFS_END)
+                .map(|end| start + end + DEFS_END.len())
+            else {
+                break;
+            };
+            let mut rest = &fragment[start + DEFS_START.len()..end - DEFS_END.len()];
+            while let Some(symbol_start) = rest.find("<symbol ") {
+                let Some(symbol_end) = rest[symbol_start..]
+                    .find("</symbol>")
+                    .map(|end| symbol_start + end + "</symbol>".len())
+                else {
+                    break;
+                };
+                let symbol = &rest[symbol_start..symbol_end];
+                let id = symbol.split('"').nth(1).unwrap_or_default();
+                if seen.insert(id.to_string()) {
+                    let renamed = format!(" id=\"{}{}\"", prefix, id);
+                    symbols.push(symbol.replacen(&format!(" id=\"{}\"", id), &renamed, 1));
+                }
+                ids.push(id.to_string());
+                rest = &rest[symbol_end..];
+            }
+            // Remove the indentation too, a blank line would end the HTML block
+            let line_start = fragment[..start].trim_end().len();
+            fragment.replace_range(line_start..end, "");
+        }
+        for id in ids {
+            *fragment =
+                fragment.replace(&format!("\"#{}\"", id), &format!("\"#{}{}\"", prefix, id));
+        }
+    }
+
+    (!symbols.is_empty()).then(|| {
+        format!(
+            concat!(
+                "<svg class=\"typst-glyphs\" aria-hidden=\"true\" ",
+                "style=\"position: absolute; width: 0; height: 0; overflow: hidden\" ",
+                "xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\">\n",
+                "<defs>\n{}\n</defs>\n</svg>"
+            ),
+            symbols.join("\n")
+        )
+    })
+}
+
+/// Rewrites the width and height of the SVG documents in `output` from pt to
+/// em, relative to `text_size` in pt.
+fn svg_size_to_em(output: &str, text_size: f64) -> String {
+    let mut result = String::with_capacity(output.len());
+    let mut rest = output;
+    while let Some(start) = rest.find("<svg class=\"typst-doc\"") {
+        let end = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |end| start + end);
+        result.push_str(&rest[..start]);
+        let mut tag = rest[start..end].to_string();
+        for attribute in ["width", "height"] {
+            let prefix = format!(" {}=\"", attribute);
+            let Some(value_start) = tag.find(&prefix).map(|i| i + prefix.len()) else {
+                continue;
+            };
+            let Some(value_len) = tag[value_start..].find("pt\"") else {
+                continue;
+            };
+            if let Ok(pt) = tag[value_start..value_start + value_len].parse::<f64>() {
+                let em = format!("{:.4}em", pt / text_size);
+                tag.replace_range(value_start..value_start + value_len + 2, &em);
+            }
+        }
+        result.push_str(&tag);
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+impl TypstProcessorOptions {
+    /// Returns the format of blocks rendered to images.
+    fn image_format(&self) -> Format {
+        match self.renderer_output {
+            RendererOutput::Png => Format::Png,
+            _ => Format::Svg,
+        }
+    }
+
+    /// Returns the preamble prepended to inline or display blocks.
+    fn preamble(&self, inline: bool) -> Preamble {
+        match inline {
+            true if self.inline_preamble.is_some() => Preamble::Inline,
+            false if self.display_preamble.is_some() => Preamble::Display,
+            _ => Preamble::Shared,
+        }
+    }
+}
+
+/// Returns the format of a code block with the language tag `lang`, or
+/// `None` if it is not a Typst block.
+fn code_format(lang: &str, opts: &TypstProcessorOptions) -> Option<Format> {
+    match lang.strip_prefix(opts.code_tag.as_str())? {
+        "" => Some(match opts.code_output {
+            CodeOutput::Svg => opts.image_format(),
+            CodeOutput::Html => Format::Html,
+        }),
+        ",svg" => Some(opts.image_format()),
+        ",html" => Some(Format::Html),
+        _ => None,
+    }
+}
+
+/// Moves the inline `style` attributes of HTML elements into CSS classes,
+/// so that the book's theme can override them.
+///
+/// The CSS rules of the classes are added to `rules`. Class names are
+/// derived from the style, so identical styles share a class.
+fn styles_to_classes(html: &str, rules: &mut Vec<String>) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+    // Text is escaped, so every `<` starts a tag
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>').map(|end| start + end + 1) else {
+            break;
+        };
+        output.push_str(&rest[..start]);
+
+        let tag = &rest[start..end];
+        let style = tag.split_once(" style=\"").and_then(|(before, after)| {
+            let (style, after) = after.split_once('"')?;
+            Some((before, style, after))
+        });
+        match style {
+            Some((before, style, after)) => {
+                let hash = format!("{:x}", Sha256::digest(style));
+                let class = format!("typst-style-{}", &hash[..8]);
+                let rule = format!(".{} {{ {} }}", class, unescape_html(style));
+                if !rules.contains(&rule) {
+                    rules.push(rule);
+                }
+
+                let tag = format!("{}{}", before, after);
+                match tag.split_once(" class=\"") {
+                    Some((before, after)) => {
+                        output.push_str(&format!("{} class=\"{} {}", before, class, after))
+                    }
+                    None => {
+                        let close = if tag.ends_with("/>") { 2 } else { 1 };
+                        let (before, after) = tag.split_at(tag.len() - close);
+                        output.push_str(&format!("{} class=\"{}\"{}", before, class, after));
+                    }
+                }
+            }
+            None => output.push_str(tag),
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Encodes newlines followed by a blank line as character references.
+///
+/// A blank line would end the HTML block in the markdown, e.g. inside a
+/// `<pre>` element, which would garble the rest of the fragment.
+fn escape_blank_lines(html: &str) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut lines = html.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        output.push_str(line);
+        match lines.peek() {
+            Some(next) if next.trim().is_empty() => output.push_str("&#10;"),
+            Some(_) => output.push('\n'),
+            None => {}
+        }
+    }
+    output
+}
+
+/// Builds a box showing the error of a block that failed to compile.
+///
+/// Like [`source_fallback`], the message is escaped for markdown as well.
+fn error_placeholder(block: &TypstBlock, location: &str, error: &CompileError) -> String {
+    let message = escape_blank_lines(&escape_markdown(&escape_html(&error.to_string())));
+    let location = escape_markdown(&escape_html(location));
+    match block.inline {
+        true => format!(
+            "<span class=\"typst-error\" title=\"{}\">{}</span>",
+            location, message
+        ),
+        false => format!(
+            "<div class=\"typst-error\"><div class=\"typst-error-location\">{}</div><pre>{}</pre></div>",
+            location, message
+        ),
+    }
+}
+
+/// Builds the fallback showing the raw source of a block that failed to compile.
+///
+/// The fragment is parsed as markdown again by mdbook, so the characters
+/// markdown would interpret are escaped as well.
+fn source_fallback(block: &TypstBlock) -> String {
+    let raw = escape_markdown(&escape_html(&block.raw));
+    match block.inline {
+        true => format!("<code class=\"typst-source\">{}</code>", raw),
+        false => format!(
+            "<pre><code class=\"typst-source\">{}</code></pre>",
+            escape_blank_lines(&raw)
+        ),
+    }
+}
+
+/// Encodes the characters with a special meaning in markdown inline content
+/// as character references.
+pub(crate) fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '*' | '_' | '`' | '\\' | '[' | ']' | '$' | '~' => {
+                escaped.push_str(&format!("&#{};", u32::from(c)));
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses [`escape_html`], e.g. for attribute values moved into CSS.
+fn unescape_html(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Escapes the characters that have a special meaning in HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baseline_shift_in_output_units() {
+        let shift = |format, units| baseline_shift(Some(0.5), Some(12.0), format, units);
+        assert_eq!(shift(Format::Svg, SvgUnits::Pt).unwrap(), "-6.0000pt");
+        assert_eq!(shift(Format::Png, SvgUnits::Pt).unwrap(), "-8.0000px");
+        assert_eq!(shift(Format::Svg, SvgUnits::Em).unwrap(), "-0.5000em");
+        assert_eq!(shift(Format::Png, SvgUnits::Em).unwrap(), "-0.5000em");
+    }
+
+    /// Creates a block with the source `raw`.
+    fn block(raw: &str, inline: bool) -> TypstBlock {
+        TypstBlock {
+            span: 0..raw.len(),
+            source: raw.to_string(),
+            inline,
+            format: Format::Svg,
+            preamble_len: 0,
+            segments: Vec::new(),
+            markdown_line: 1,
+            raw: raw.to_string(),
+        }
+    }
+
+    #[test]
+    fn error_placeholder_escapes_markdown() {
+        let error = CompileError::Unsupported(String::from("`a` * \\b_c"));
+        assert_eq!(
+            error_placeholder(&block("$x$", true), "c.md:1", &error),
+            "<span class=\"typst-error\" title=\"c.md:1\">Unsupported content: \
+             &#96;a&#96; &#42; &#92;b&#95;c</span>"
+        );
+    }
+
+    #[test]
+    fn source_fallback_escapes_markdown() {
+        assert_eq!(
+            source_fallback(&block("$a_1 * [b] `c` \\d <e>$", true)),
+            "<code class=\"typst-source\">&#36;a&#95;1 &#42; &#91;b&#93; &#96;c&#96; &#92;d &lt;e&gt;&#36;</code>"
+        );
+        assert_eq!(
+            source_fallback(&block("$$\n*a*\n\nb\n$$", false)),
+            "<pre><code class=\"typst-source\">&#36;&#36;\n&#42;a&#42;&#10;\nb\n&#36;&#36;</code></pre>"
+        );
+    }
+
+    #[test]
+    fn styles_move_into_classes() {
+        let mut rules = Vec::new();
+        let html = styles_to_classes(
+            "<p style=\"color: red\">a</p><b class=\"x\" style=\"color: red\">&lt;b&gt;</b>\
+             <img style=\"width: 1em\"/>",
+            &mut rules,
+        );
+        let red = format!(
+            "typst-style-{}",
+            &format!("{:x}", Sha256::digest("color: red"))[..8]
+        );
+        let width = format!(
+            "typst-style-{}",
+            &format!("{:x}", Sha256::digest("width: 1em"))[..8]
+        );
+        assert_eq!(
+            html,
+            format!(
+                "<p class=\"{red}\">a</p><b class=\"{red} x\">&lt;b&gt;</b><img class=\"{width}\"/>"
+            )
+        );
+        assert_eq!(
+            rules,
+            [
+                format!(".{red} {{ color: red }}"),
+                format!(".{width} {{ width: 1em }}")
+            ]
+        );
+    }
 
-        Ok(content)
+    #[test]
+    fn baseline_shift_needs_probe() {
+        assert_eq!(
+            baseline_shift(None, Some(12.0), Format::Svg, SvgUnits::Pt),
+            None
+        );
+        assert_eq!(
+            baseline_shift(Some(0.5), None, Format::Svg, SvgUnits::Pt),
+            None
+        );
+        assert_eq!(
+            baseline_shift(Some(0.5), None, Format::Svg, SvgUnits::Em).unwrap(),
+            "-0.5000em"
+        );
     }
 }
@@ -0,0 +1,436 @@
+//! Lockfile recording the packages a book was built with.
+//!
+//! This module provides a [`PackageLock`] that records the SHA-256 of the
+//! archive and of the files of every resolved package in
+//! `typst-packages.lock`, and verifies packages against it, so that every
+//! machine builds the book with the same package contents.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use typst::{
+    diag::{eco_format, EcoString, PackageError, PackageResult},
+    syntax::package::PackageSpec,
+};
+
+/// Name of the lockfile, next to `book.toml`.
+pub const LOCK_FILE: &str = "typst-packages.lock";
+
+/// Header written at the top of the lockfile.
+const LOCK_HEADER: &str =
+    "# This file is generated by mdbook-typst-math. Do not edit it by hand.\n";
+
+/// How the package lockfile is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockMode {
+    /// Do not use a lockfile.
+    ///
+    /// This is the default.
+    #[default]
+    Off,
+    /// Verify packages against the lockfile, warning on mismatches, and add
+    /// packages that are not locked yet.
+    Verify,
+    /// Fail on packages that do not match the lockfile or are not locked.
+    /// The lockfile is never written.
+    Strict,
+    /// Regenerate the lockfile from the packages resolved in this build.
+    Update,
+}
+
+impl std::str::FromStr for LockMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(LockMode::Off),
+            "verify" => Ok(LockMode::Verify),
+            "strict" => Ok(LockMode::Strict),
+            "update" => Ok(LockMode::Update),
+            _ => Err(format!("unknown lock mode: {s}")),
+        }
+    }
+}
+
+/// Checksums of a locked package.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct LockedPackage {
+    /// The package spec, e.g. `@preview/physica:0.9.7`.
+    spec: String,
+    /// SHA-256 of the package archive, if the package was extracted from one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archive: Option<String>,
+    /// SHA-256 of the files of the package, see [`files_hash`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<String>,
+}
+
+/// The persisted lockfile.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct LockFile {
+    /// Locked packages, sorted by spec.
+    #[serde(default, rename = "package")]
+    packages: Vec<LockedPackage>,
+}
+
+/// Which checksum of a package is verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Checksum {
+    Archive,
+    Files,
+}
+
+/// The package lockfile of a book.
+///
+/// Packages are verified when their archive is extracted, see
+/// [`PackageLock::verify_archive`], and when they are first resolved in a
+/// build, see [`PackageLock::verify_files`]. The lockfile is only written
+/// by [`PackageLock::save`].
+pub struct PackageLock {
+    path: PathBuf,
+    mode: LockMode,
+    /// Packages read from the lockfile, by spec.
+    locked: HashMap<String, LockedPackage>,
+    /// Packages resolved in this build, by spec.
+    resolved: Mutex<BTreeMap<String, LockedPackage>>,
+    /// Outcome of verifying the files of each package, so that packages are
+    /// only hashed once per build.
+    verified: Mutex<HashMap<PackageSpec, Result<(), EcoString>>>,
+    /// Number of packages that did not match the lockfile.
+    mismatches: Mutex<usize>,
+}
+
+impl PackageLock {
+    /// Opens the lockfile at `path`.
+    ///
+    /// A missing lockfile is treated as empty. In [`LockMode::Update`], the
+    /// existing lockfile is ignored.
+    pub fn open(path: impl Into<PathBuf>, mode: LockMode) -> Result<Self, String> {
+        let path = path.into();
+        let locked = match fs::read_to_string(&path) {
+            Ok(_) if mode == LockMode::Update => Vec::new(),
+            Ok(contents) => {
+                toml::from_str::<LockFile>(&contents)
+                    .map_err(|e| format!("Invalid {}: {}", path.display(), e))?
+                    .packages
+            }
+            Err(_) => Vec::new(),
+        };
+        Ok(Self {
+            path,
+            mode,
+            locked: locked
+                .into_iter()
+                .map(|package| (package.spec.clone(), package))
+                .collect(),
+            resolved: Mutex::new(BTreeMap::new()),
+            verified: Mutex::new(HashMap::new()),
+            mismatches: Mutex::new(0),
+        })
+    }
+
+    /// Returns the path of the lockfile.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns how the lockfile is used.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns the number of packages that did not match the lockfile.
+    pub fn mismatches(&self) -> usize {
+        *self.mismatches.lock().unwrap()
+    }
+
+    /// Verifies the archive of a package before it is extracted.
+    pub fn verify_archive(&self, spec: &PackageSpec, archive: &[u8]) -> PackageResult<()> {
+        self.verify_archive_hash(spec, archive_hash(archive))
+    }
+
+    /// Verifies the SHA-256 of the archive a cached package was extracted
+    /// from, see [`archive_hash`].
+    pub fn verify_archive_hash(&self, spec: &PackageSpec, hash: String) -> PackageResult<()> {
+        self.verify(spec, Checksum::Archive, hash)
+            .map_err(|message| PackageError::Other(Some(message)))
+    }
+
+    /// Verifies the files of a resolved package in `dir`.
+    ///
+    /// The files are only hashed the first time a package is verified.
+    pub fn verify_files(&self, spec: &PackageSpec, dir: &Path) -> PackageResult<()> {
+        let mut verified = self.verified.lock().unwrap();
+        let result = match verified.get(spec) {
+            Some(result) => result.clone(),
+            None => {
+                let result = files_hash(dir)
+                    .map_err(|e| eco_format!("Failed to hash package {}: {}", spec, e))
+                    .and_then(|hash| self.verify(spec, Checksum::Files, hash));
+                verified.insert(spec.clone(), result.clone());
+                result
+            }
+        };
+        result.map_err(|message| PackageError::Other(Some(message)))
+    }
+
+    /// Compares a checksum with the lockfile and records it.
+    fn verify(&self, spec: &PackageSpec, kind: Checksum, hash: String) -> Result<(), EcoString> {
+        let key = spec.to_string();
+        let entry = self.locked.get(&key);
+        let locked = entry.and_then(|package| match kind {
+            Checksum::Archive => package.archive.as_ref(),
+            Checksum::Files => package.files.as_ref(),
+        });
+        let name = match kind {
+            Checksum::Archive => "archive",
+            Checksum::Files => "files",
+        };
+
+        match locked {
+            Some(locked) if *locked != hash => {
+                *self.mismatches.lock().unwrap() += 1;
+                let message = eco_format!(
+                    "{} does not match {}: the SHA-256 of its {} is {}, but {} is locked",
+                    spec,
+                    LOCK_FILE,
+                    name,
+                    hash,
+                    locked
+                );
+                if self.mode == LockMode::Strict {
+                    return Err(message);
+                }
+                warn!("{}", message);
+                // Keep the locked checksum, the lockfile is only changed on update
+                return Ok(());
+            }
+            // Packages are locked with the checksums available when they were
+            // resolved, e.g. only the files of a local package, so only
+            // packages without an entry are missing
+            None if entry.is_none() && self.mode == LockMode::Strict => {
+                *self.mismatches.lock().unwrap() += 1;
+                return Err(eco_format!(
+                    "{} is not locked in {}, regenerate it with `lock = \"update\"`",
+                    spec,
+                    LOCK_FILE
+                ));
+            }
+            _ => {}
+        }
+
+        let mut resolved = self.resolved.lock().unwrap();
+        let package = resolved
+            .entry(key.clone())
+            .or_insert_with(|| LockedPackage {
+                spec: key,
+                ..LockedPackage::default()
+            });
+        match kind {
+            Checksum::Archive => package.archive = Some(hash),
+            Checksum::Files => package.files = Some(hash),
+        }
+        Ok(())
+    }
+
+    /// Writes the lockfile if it changed.
+    ///
+    /// In [`LockMode::Verify`], the packages resolved in this build are added
+    /// to the locked ones. In [`LockMode::Update`], the lockfile only lists
+    /// the packages resolved in this build.
+    pub fn save(&self) -> std::io::Result<()> {
+        if matches!(self.mode, LockMode::Off | LockMode::Strict) {
+            return Ok(());
+        }
+
+        let mut packages: BTreeMap<String, LockedPackage> = self
+            .locked
+            .iter()
+            .map(|(spec, package)| (spec.clone(), package.clone()))
+            .collect();
+        for (spec, resolved) in self.resolved.lock().unwrap().iter() {
+            let package = packages
+                .entry(spec.clone())
+                .or_insert_with(|| LockedPackage {
+                    spec: spec.clone(),
+                    ..LockedPackage::default()
+                });
+            package.archive = package.archive.take().or(resolved.archive.clone());
+            package.files = package.files.take().or(resolved.files.clone());
+        }
+
+        let lock = LockFile {
+            packages: packages.into_values().collect(),
+        };
+        let contents = toml::to_string(&lock).map_err(std::io::Error::other)?;
+        let contents = format!("{}\n{}", LOCK_HEADER, contents);
+        if fs::read_to_string(&self.path).ok().as_deref() == Some(contents.as_str()) {
+            return Ok(());
+        }
+        fs::write(&self.path, contents)
+    }
+}
+
+/// Computes the SHA-256 of a package archive.
+pub fn archive_hash(archive: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(archive))
+}
+
+/// Computes the SHA-256 of the files in a package directory.
+///
+/// The hash covers the relative path and the contents of every file, in
+/// sorted order, so it does not depend on how the package was stored.
+fn files_hash(dir: &Path) -> std::io::Result<String> {
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for (relative, path) in files {
+        let data = fs::read(path)?;
+        hasher.update(relative.as_bytes());
+        hasher.update([0]);
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(data);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Collects the files below `dir` with their paths relative to `root`,
+/// using `/` as separator.
+fn collect_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<(String, PathBuf)>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            let relative = relative
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((relative, path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the path of a lockfile for a test, removing any left over.
+    fn lock_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("mdbook-typst-math-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    /// Writes a lockfile locking the archive of `@preview/a:0.1.0`.
+    fn write_lock(path: &Path) {
+        let contents = format!(
+            "{}\n[[package]]\nspec = \"@preview/a:0.1.0\"\narchive = \"{}\"\n",
+            LOCK_HEADER,
+            archive_hash(b"a")
+        );
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Parses a package spec.
+    fn spec(spec: &str) -> PackageSpec {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn verify_warns_on_mismatch() {
+        let path = lock_path("verify.lock");
+        write_lock(&path);
+        let lock = PackageLock::open(&path, LockMode::Verify).unwrap();
+
+        assert!(lock.verify_archive(&spec("@preview/a:0.1.0"), b"a").is_ok());
+        assert!(lock
+            .verify_archive(&spec("@preview/a:0.1.0"), b"tampered")
+            .is_ok());
+        assert!(lock.verify_archive(&spec("@preview/b:0.1.0"), b"b").is_ok());
+        assert_eq!(lock.mismatches(), 1);
+
+        // The locked checksum is kept, new packages are added
+        lock.save().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(&archive_hash(b"a")));
+        assert!(!contents.contains(&archive_hash(b"tampered")));
+        assert!(contents.contains(&archive_hash(b"b")));
+    }
+
+    #[test]
+    fn strict_fails_on_mismatch() {
+        let path = lock_path("strict-mismatch.lock");
+        write_lock(&path);
+        let lock = PackageLock::open(&path, LockMode::Strict).unwrap();
+
+        assert!(lock.verify_archive(&spec("@preview/a:0.1.0"), b"a").is_ok());
+        let error = lock
+            .verify_archive(&spec("@preview/a:0.1.0"), b"tampered")
+            .unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{}", error);
+        assert_eq!(lock.mismatches(), 1);
+    }
+
+    #[test]
+    fn strict_fails_on_unlocked_package() {
+        let path = lock_path("strict-unlocked.lock");
+        write_lock(&path);
+        let lock = PackageLock::open(&path, LockMode::Strict).unwrap();
+
+        let error = lock
+            .verify_archive(&spec("@preview/b:0.1.0"), b"b")
+            .unwrap_err();
+        assert!(error.to_string().contains("is not locked"), "{}", error);
+
+        // The lockfile is never written
+        lock.save().unwrap();
+        assert!(!fs::read_to_string(&path)
+            .unwrap()
+            .contains(&archive_hash(b"b")));
+    }
+
+    #[test]
+    fn update_rewrites() {
+        let path = lock_path("update.lock");
+        write_lock(&path);
+        let lock = PackageLock::open(&path, LockMode::Update).unwrap();
+
+        assert!(lock
+            .verify_archive(&spec("@preview/a:0.1.0"), b"new")
+            .is_ok());
+        assert!(lock.verify_archive(&spec("@preview/c:0.1.0"), b"c").is_ok());
+        assert_eq!(lock.mismatches(), 0);
+
+        lock.save().unwrap();
+        let locked = PackageLock::open(&path, LockMode::Verify).unwrap();
+        assert_eq!(locked.locked.len(), 2);
+        assert_eq!(
+            locked.locked["@preview/a:0.1.0"].archive,
+            Some(archive_hash(b"new"))
+        );
+        assert_eq!(
+            locked.locked["@preview/c:0.1.0"].archive,
+            Some(archive_hash(b"c"))
+        );
+    }
+}
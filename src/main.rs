@@ -40,7 +40,7 @@ fn main() {
 
     let cli = Cli::parse();
 
-    let pre = mdbook_typst_math::TypstProcessor;
+    let pre = mdbook_typst_math::TypstProcessor::new();
 
     match cli.command {
         Some(Command::Supports { renderer }) => {
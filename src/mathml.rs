@@ -0,0 +1,532 @@
+//! Conversion of Typst equations to MathML.
+//!
+//! Typst's HTML export does not emit equations yet, but it still evaluates
+//! them, so the [`EquationElem`](typst::math::EquationElem)s of a document compiled for the HTML target
+//! can be queried and converted to native `<math>` markup here.
+//!
+//! Only constructs with a direct MathML counterpart are supported. For
+//! anything else (e.g. styled content like `bold(x)` or `cancel(x)`) the
+//! conversion fails with the name of the construct, so that callers can fall
+//! back to SVG.
+
+use typst::foundations::{Content, Repr, Value};
+
+/// Large operators whose attachments are placed above and below in display math.
+const LARGE_OPERATORS: &str = "∑∏∐⋀⋁⋂⋃⨀⨁⨂⨄⨆";
+
+/// Converts an equation to a `<math>` element.
+///
+/// # Errors
+///
+/// Returns a description of the first construct that cannot be expressed in
+/// MathML.
+pub fn equation(elem: &Content) -> Result<String, String> {
+    let display = matches!(elem.get_by_name("block"), Ok(Value::Bool(true)));
+    let body = field(elem, "body").ok_or_else(|| String::from("equation"))?;
+    let converter = Converter { display };
+    let body = converter.body(&body)?;
+    Ok(match display {
+        true => format!("<math display=\"block\">{body}</math>"),
+        false => format!("<math>{body}</math>"),
+    })
+}
+
+/// Returns the content field `name` of `elem`, if it is set.
+fn field(elem: &Content, name: &str) -> Option<Content> {
+    match elem.get_by_name(name).ok()? {
+        Value::None => None,
+        value => Some(value.display()),
+    }
+}
+
+/// Returns the fields `name` of `elem` that hold a list of content.
+fn children(elem: &Content, name: &str) -> Vec<Content> {
+    match elem.get_by_name(name) {
+        Ok(Value::Array(array)) => array.into_iter().map(Value::display).collect(),
+        Ok(Value::None) | Err(_) => Vec::new(),
+        Ok(value) => vec![value.display()],
+    }
+}
+
+/// Converts the content of an equation to MathML.
+struct Converter {
+    /// Whether the equation is display math.
+    display: bool,
+}
+
+impl Converter {
+    /// Converts the body of an equation, which may span multiple aligned lines.
+    fn body(&self, body: &Content) -> Result<String, String> {
+        let items = match body.func().name() {
+            "sequence" => children(body, "children"),
+            _ => vec![body.clone()],
+        };
+        let multiline = items
+            .iter()
+            .any(|item| matches!(item.func().name(), "linebreak" | "align-point"));
+        if !multiline {
+            return self.row(body);
+        }
+
+        // Lines become table rows and alignment points separate the cells,
+        // alternating between right and left alignment like in Typst
+        let mut rows = vec![vec![Vec::new()]];
+        for item in items {
+            match item.func().name() {
+                "linebreak" => rows.push(vec![Vec::new()]),
+                "align-point" => rows.last_mut().unwrap().push(Vec::new()),
+                _ => rows.last_mut().unwrap().last_mut().unwrap().push(item),
+            }
+        }
+        let mut table = String::from("<mtable columnalign=\"right left\" displaystyle=\"true\">");
+        for row in rows {
+            table.push_str("<mtr>");
+            for cell in row {
+                table.push_str(&format!(
+                    "<mtd>{}</mtd>",
+                    self.row(&Content::sequence(cell))?
+                ));
+            }
+            table.push_str("</mtr>");
+        }
+        table.push_str("</mtable>");
+        Ok(table)
+    }
+
+    /// Converts content to a single `<mrow>`.
+    fn row(&self, content: &Content) -> Result<String, String> {
+        Ok(format!("<mrow>{}</mrow>", self.convert(content)?))
+    }
+
+    /// Converts an optional content field to a single `<mrow>`.
+    fn field_row(&self, elem: &Content, name: &str) -> Result<Option<String>, String> {
+        field(elem, name)
+            .map(|content| self.row(&content))
+            .transpose()
+    }
+
+    /// Converts content to a sequence of MathML elements.
+    fn convert(&self, content: &Content) -> Result<String, String> {
+        let name = content.func().name();
+        let output = match name {
+            "sequence" => children(content, "children")
+                .iter()
+                .map(|child| self.convert(child))
+                .collect::<Result<String, _>>()?,
+            // MathML takes care of the spacing
+            "space" => String::new(),
+            "symbol" => token(&text(content, "text")),
+            "text" => {
+                let text = text(content, "text");
+                match is_number(&text) {
+                    true => format!("<mn>{}</mn>", escape(&text)),
+                    false => format!("<mtext>{}</mtext>", escape(&text)),
+                }
+            }
+            "op" => format!("<mi>{}</mi>", escape(&content.plain_text())),
+            "h" => match content.get_by_name("amount") {
+                Ok(amount) => spacing(&amount.repr()),
+                Err(_) => String::new(),
+            },
+            "class" | "stretch" | "limits" | "scripts" => match field(content, "body") {
+                Some(body) => self.convert(&body)?,
+                None => String::new(),
+            },
+            "lr" => self.row(&field(content, "body").unwrap_or_default())?,
+            "mid" => format!(
+                "<mo stretchy=\"true\">{}</mo>",
+                escape(&field(content, "body").unwrap_or_default().plain_text())
+            ),
+            "attach" => self.attach(content)?,
+            "primes" => {
+                let count = match content.get_by_name("count") {
+                    Ok(Value::Int(count)) => count.max(0) as usize,
+                    _ => 1,
+                };
+                format!("<mo>{}</mo>", primes(count))
+            }
+            "frac" => format!(
+                "<mfrac>{}{}</mfrac>",
+                self.row(&field(content, "num").unwrap_or_default())?,
+                self.row(&field(content, "denom").unwrap_or_default())?,
+            ),
+            "binom" => {
+                let lower = Content::sequence(children(content, "lower"));
+                format!(
+                    "<mrow><mo>(</mo><mfrac linethickness=\"0\">{}{}</mfrac><mo>)</mo></mrow>",
+                    self.row(&field(content, "upper").unwrap_or_default())?,
+                    self.row(&lower)?,
+                )
+            }
+            "root" => {
+                let radicand = self.row(&field(content, "radicand").unwrap_or_default())?;
+                match self.field_row(content, "index")? {
+                    Some(index) => format!("<mroot>{radicand}{index}</mroot>"),
+                    None => format!("<msqrt>{radicand}</msqrt>"),
+                }
+            }
+            "accent" => {
+                let base = self.row(&field(content, "base").unwrap_or_default())?;
+                let accent = spacing_accent(&text(content, "accent"));
+                format!(
+                    "<mover accent=\"true\">{base}<mo>{}</mo></mover>",
+                    escape(&accent)
+                )
+            }
+            "overline" | "underline" => {
+                let body = self.row(&field(content, "body").unwrap_or_default())?;
+                match name {
+                    "overline" => format!("<mover accent=\"true\">{body}<mo>‾</mo></mover>"),
+                    _ => format!("<munder accentunder=\"true\">{body}<mo>&#95;</mo></munder>"),
+                }
+            }
+            "overbrace" | "overbracket" | "overparen" | "overshell" => {
+                self.brace(content, "mover", overbrace(name))?
+            }
+            "underbrace" | "underbracket" | "underparen" | "undershell" => {
+                self.brace(content, "munder", underbrace(name))?
+            }
+            "mat" => {
+                let rows = match content.get_by_name("rows") {
+                    Ok(Value::Array(rows)) => rows
+                        .into_iter()
+                        .map(|row| match row {
+                            Value::Array(cells) => cells.into_iter().map(Value::display).collect(),
+                            value => vec![value.display()],
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                self.table(content, rows, ("(", ")"), "center")?
+            }
+            "vec" => {
+                let rows = children(content, "children")
+                    .into_iter()
+                    .map(|child| vec![child])
+                    .collect();
+                self.table(content, rows, ("(", ")"), "center")?
+            }
+            "cases" => {
+                let rows = children(content, "children")
+                    .into_iter()
+                    .map(|child| vec![child])
+                    .collect();
+                self.table(content, rows, ("{", ""), "left")?
+            }
+            // Styles like `bold(x)` have no direct counterpart
+            "styled" => return Err(String::from("styled content")),
+            _ => return Err(format!("`{name}`")),
+        };
+        Ok(output)
+    }
+
+    /// Converts attachments to scripts or limits around the base.
+    fn attach(&self, content: &Content) -> Result<String, String> {
+        let base_content = field(content, "base").unwrap_or_default();
+        let base = self.row(&base_content)?;
+        let top = match self.field_row(content, "t")? {
+            Some(top) => Some(top),
+            None => self.field_row(content, "tr")?,
+        };
+        let bottom = match self.field_row(content, "b")? {
+            Some(bottom) => Some(bottom),
+            None => self.field_row(content, "br")?,
+        };
+        let top_left = self.field_row(content, "tl")?;
+        let bottom_left = self.field_row(content, "bl")?;
+
+        if top_left.is_some() || bottom_left.is_some() {
+            let none = String::from("<mrow></mrow>");
+            return Ok(format!(
+                "<mmultiscripts>{base}{}{}<mprescripts/>{}{}</mmultiscripts>",
+                bottom.as_ref().unwrap_or(&none),
+                top.as_ref().unwrap_or(&none),
+                bottom_left.as_ref().unwrap_or(&none),
+                top_left.as_ref().unwrap_or(&none),
+            ));
+        }
+
+        let (over, under, both) = match self.limits(&base_content) {
+            true => ("mover", "munder", "munderover"),
+            false => ("msup", "msub", "msubsup"),
+        };
+        Ok(match (bottom, top) {
+            (Some(bottom), Some(top)) => format!("<{both}>{base}{bottom}{top}</{both}>"),
+            (Some(bottom), None) => format!("<{under}>{base}{bottom}</{under}>"),
+            (None, Some(top)) => format!("<{over}>{base}{top}</{over}>"),
+            (None, None) => base,
+        })
+    }
+
+    /// Whether attachments of `base` are placed above and below it.
+    fn limits(&self, base: &Content) -> bool {
+        match base.func().name() {
+            "limits" => true,
+            "scripts" => false,
+            "op" => self.display && matches!(base.get_by_name("limits"), Ok(Value::Bool(true))),
+            "symbol" => {
+                let text = text(base, "text");
+                self.display && text.chars().count() == 1 && LARGE_OPERATORS.contains(&*text)
+            }
+            _ => false,
+        }
+    }
+
+    /// Converts a brace with an optional annotation above or below the body.
+    fn brace(&self, content: &Content, tag: &str, brace: &str) -> Result<String, String> {
+        let body = self.row(&field(content, "body").unwrap_or_default())?;
+        let braced = format!("<{tag}>{body}<mo stretchy=\"true\">{brace}</mo></{tag}>");
+        Ok(match self.field_row(content, "annotation")? {
+            Some(annotation) => format!("<{tag}>{braced}{annotation}</{tag}>"),
+            None => braced,
+        })
+    }
+
+    /// Converts rows of cells to a table surrounded by delimiters.
+    fn table(
+        &self,
+        content: &Content,
+        rows: Vec<Vec<Content>>,
+        default_delim: (&str, &str),
+        align: &str,
+    ) -> Result<String, String> {
+        let (open, close) = match content.get_by_name("delim") {
+            Ok(Value::None) => (String::new(), String::new()),
+            Ok(Value::Str(open)) => {
+                let close = closing_delimiter(&open);
+                (open.to_string(), close)
+            }
+            Ok(Value::Array(delims)) => {
+                let mut delims = delims.into_iter().map(|delim| match delim {
+                    Value::Str(delim) => delim.to_string(),
+                    _ => String::new(),
+                });
+                (
+                    delims.next().unwrap_or_default(),
+                    delims.next().unwrap_or_default(),
+                )
+            }
+            _ => (default_delim.0.to_string(), default_delim.1.to_string()),
+        };
+
+        let mut table = format!("<mtable columnalign=\"{align}\">");
+        for row in rows {
+            table.push_str("<mtr>");
+            for cell in row {
+                table.push_str(&format!("<mtd>{}</mtd>", self.row(&cell)?));
+            }
+            table.push_str("</mtr>");
+        }
+        table.push_str("</mtable>");
+
+        let open = match open.is_empty() {
+            true => String::new(),
+            false => format!("<mo>{}</mo>", escape(&open)),
+        };
+        let close = match close.is_empty() {
+            true => String::new(),
+            false => format!("<mo>{}</mo>", escape(&close)),
+        };
+        Ok(format!("<mrow>{open}{table}{close}</mrow>"))
+    }
+}
+
+/// Returns the text of the field `name` of `elem`.
+fn text(elem: &Content, name: &str) -> String {
+    match elem.get_by_name(name) {
+        Ok(Value::Str(text)) => text.to_string(),
+        Ok(value) => value.display().plain_text().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+/// Converts a piece of text to a token element depending on its contents.
+fn token(text: &str) -> String {
+    let mut chars = text.chars();
+    let tag = match (chars.next(), chars.next()) {
+        _ if is_number(text) => "mn",
+        (Some(c), None) if c.is_alphabetic() => "mi",
+        (Some(c), None) if c.is_numeric() => "mn",
+        (Some(_), None) => "mo",
+        _ => "mtext",
+    };
+    format!("<{tag}>{}</{tag}>", escape(text))
+}
+
+/// Whether `text` is a number like `1` or `1.5`.
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+/// Returns the spacing form of a combining accent, which renders better on
+/// its own in `<mover>`.
+fn spacing_accent(accent: &str) -> String {
+    match accent {
+        "\u{300}" => "`",
+        "\u{301}" => "´",
+        "\u{302}" => "^",
+        "\u{303}" => "~",
+        "\u{304}" => "¯",
+        "\u{306}" => "˘",
+        "\u{307}" => "˙",
+        "\u{308}" => "¨",
+        "\u{30a}" => "˚",
+        "\u{30c}" => "ˇ",
+        "\u{20d7}" => "→",
+        other => other,
+    }
+    .to_string()
+}
+
+/// Converts horizontal spacing in `em` to an `<mspace>`.
+fn spacing(amount: &str) -> String {
+    match amount.strip_suffix("em").map(str::parse::<f64>) {
+        Some(Ok(width)) => format!("<mspace width=\"{width}em\"/>"),
+        _ => String::new(),
+    }
+}
+
+/// Returns the prime symbol for `count` primes.
+fn primes(count: usize) -> String {
+    match count {
+        1 => String::from("′"),
+        2 => String::from("″"),
+        3 => String::from("‴"),
+        4 => String::from("⁗"),
+        _ => "′".repeat(count),
+    }
+}
+
+/// Returns the stretchy symbol placed above the body.
+fn overbrace(name: &str) -> &'static str {
+    match name {
+        "overbracket" => "⎴",
+        "overparen" => "⏜",
+        "overshell" => "⏠",
+        _ => "⏞",
+    }
+}
+
+/// Returns the stretchy symbol placed below the body.
+fn underbrace(name: &str) -> &'static str {
+    match name {
+        "underbracket" => "⎵",
+        "underparen" => "⏝",
+        "undershell" => "⏡",
+        _ => "⏟",
+    }
+}
+
+/// Returns the closing delimiter matching `open`.
+fn closing_delimiter(open: &str) -> String {
+    match open {
+        "(" => ")",
+        "[" => "]",
+        "{" => "}",
+        "⟨" => "⟩",
+        "⌈" => "⌉",
+        "⌊" => "⌋",
+        other => other,
+    }
+    .to_string()
+}
+
+/// Escapes text for use in MathML.
+///
+/// The MathML ends up in markdown, so the characters markdown would
+/// interpret are escaped as well.
+fn escape(text: &str) -> String {
+    crate::escape_markdown(
+        &text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{Compiler, Format, Origin};
+
+    /// Converts the Typst math `math` to MathML.
+    fn mathml(math: &str) -> String {
+        Compiler::new()
+            .render(math, Origin::default(), Format::Mathml)
+            .unwrap()
+            .output
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(
+            mathml("$a/b$"),
+            "<math><mrow><mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            mathml("$x_1^2$"),
+            "<math><mrow><msubsup><mrow><mi>x</mi></mrow><mrow><mn>1</mn></mrow>\
+             <mrow><mn>2</mn></mrow></msubsup></mrow></math>"
+        );
+        // Limits of large operators move above and below in display math
+        assert!(mathml("$sum_(i=0)^n i$").contains("<msubsup><mrow><mo>∑</mo>"));
+        assert!(mathml("$ sum_(i=0)^n i $").contains("<munderover><mrow><mo>∑</mo>"));
+    }
+
+    #[test]
+    fn roots() {
+        assert_eq!(
+            mathml("$sqrt(x) + root(3, y)$"),
+            "<math><mrow><msqrt><mrow><mi>x</mi></mrow></msqrt><mo>+</mo>\
+             <mroot><mrow><mi>y</mi></mrow><mrow><mn>3</mn></mrow></mroot></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn matrices() {
+        assert_eq!(
+            mathml("$mat(1, 2; 3, 4)$"),
+            "<math><mrow><mrow><mo>(</mo><mtable columnalign=\"center\">\
+             <mtr><mtd><mrow><mn>1</mn></mrow></mtd><mtd><mrow><mn>2</mn></mrow></mtd></mtr>\
+             <mtr><mtd><mrow><mn>3</mn></mrow></mtd><mtd><mrow><mn>4</mn></mrow></mtd></mtr>\
+             </mtable><mo>)</mo></mrow></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn accents() {
+        assert_eq!(
+            mathml("$hat(x) + underline(y)$"),
+            "<math><mrow><mover accent=\"true\"><mrow><mi>x</mi></mrow><mo>^</mo></mover>\
+             <mo>+</mo><munder accentunder=\"true\"><mrow><mi>y</mi></mrow><mo>&#95;</mo>\
+             </munder></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            mathml("$\"a_b*c\" < d$"),
+            "<math><mrow><mtext>a&#95;b&#42;c</mtext><mo>&lt;</mo><mi>d</mi></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn escaping_backslash() {
+        assert_eq!(
+            mathml("$a backslash b$"),
+            "<math><mrow><mi>a</mi><mo>&#92;</mo><mi>b</mi></mrow></math>"
+        );
+    }
+
+    #[test]
+    fn escaping_backtick() {
+        assert_eq!(
+            mathml("$\"`a` [b]\"$"),
+            "<math><mrow><mtext>&#96;a&#96; &#91;b&#93;</mtext></mrow></math>"
+        );
+    }
+}
@@ -0,0 +1,663 @@
+//! Resolution of Typst packages.
+//!
+//! This module provides the [`PackageProvider`] trait, which resolves a
+//! package to the directory containing its files, and the built-in
+//! providers:
+//!
+//! - [`LocalPackages`]: a local package directory, like the Typst CLI's
+//!   package data directory
+//! - [`HttpRegistry`]: a registry serving package archives over HTTP, like
+//!   `packages.typst.org`
+//! - [`LocalRegistry`]: a local directory standing in for a registry
+//! - [`MemoryPackages`]: packages defined in memory, e.g. as test fixtures
+//! - [`PackageChain`]: a list of providers tried in order
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use typst::{
+    diag::{eco_format, PackageError, PackageResult},
+    syntax::package::PackageSpec,
+};
+
+use crate::lock::{archive_hash, PackageLock};
+
+/// Environment variable overriding the Typst package cache directory.
+const PACKAGE_CACHE_ENV: &str = "TYPST_PACKAGE_CACHE_PATH";
+
+/// Environment variable overriding the Typst package data directory.
+const PACKAGE_PATH_ENV: &str = "TYPST_PACKAGE_PATH";
+
+/// Base URL of the `@preview` packages on Typst Universe.
+pub const PREVIEW_URL: &str = "https://packages.typst.org/preview";
+
+/// Returns the directory the Typst CLI caches downloaded packages in.
+///
+/// This is `$TYPST_PACKAGE_CACHE_PATH` if set, or `typst/packages` in the
+/// platform's cache directory, e.g. `~/.cache/typst/packages` on Linux.
+pub fn typst_package_cache() -> Option<PathBuf> {
+    std::env::var_os(PACKAGE_CACHE_ENV)
+        .map(PathBuf::from)
+        .or_else(|| dirs::cache_dir().map(|dir| dir.join("typst/packages")))
+}
+
+/// Returns the directory the Typst CLI looks up local packages in, such as
+/// `@local` packages.
+///
+/// This is `$TYPST_PACKAGE_PATH` if set, or `typst/packages` in the
+/// platform's data directory, e.g. `~/.local/share/typst/packages` on Linux.
+pub fn typst_package_path() -> Option<PathBuf> {
+    std::env::var_os(PACKAGE_PATH_ENV)
+        .map(PathBuf::from)
+        .or_else(|| dirs::data_dir().map(|dir| dir.join("typst/packages")))
+}
+
+/// Resolves Typst packages to directories containing their files.
+///
+/// Providers are shared between the threads rendering blocks, and may be
+/// asked for the same package many times, also at the same time, so they
+/// must fetch each package only once. The built-in providers do, and
+/// extract archives atomically, so that several builds can share a cache.
+///
+/// # Example
+///
+/// ```ignore
+/// use mdbook_typst_math::{PackageChain, MemoryPackages, TypstProcessor};
+///
+/// let mut fixtures = MemoryPackages::new("target/test-packages");
+/// fixtures.insert(
+///     "@local/macros:0.1.0".parse().unwrap(),
+///     "lib.typ",
+///     "#let half = $1/2$",
+/// );
+/// let processor = TypstProcessor::new().with_package_provider(fixtures);
+/// ```
+pub trait PackageProvider: Send + Sync {
+    /// Returns the directory containing the files of a package, with its
+    /// `typst.toml` at the top level.
+    ///
+    /// Returns [`PackageError::NotFound`] if the provider does not have the
+    /// package, so that a [`PackageChain`] tries the next provider.
+    fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf>;
+
+    /// Returns where the provider looks for a package, listed in the error
+    /// when no provider of a [`PackageChain`] has it.
+    fn locations(&self, _spec: &PackageSpec) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl<P: PackageProvider + ?Sized> PackageProvider for Arc<P> {
+    fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        (**self).resolve(spec)
+    }
+
+    fn locations(&self, spec: &PackageSpec) -> Vec<String> {
+        (**self).locations(spec)
+    }
+}
+
+/// A list of providers, tried in order until one has the package.
+#[derive(Default)]
+pub struct PackageChain {
+    providers: Vec<Box<dyn PackageProvider>>,
+}
+
+impl PackageChain {
+    /// Creates an empty chain, which has no packages.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the chain used by the Typst CLI: the package data directory
+    /// and `@preview` packages from Typst Universe, downloaded to the
+    /// package cache directory.
+    #[must_use]
+    pub fn typst() -> Self {
+        let mut chain = Self::new();
+        if let Some(dir) = typst_package_path() {
+            chain.push(LocalPackages::new(dir));
+        }
+        chain.push(HttpRegistry::new(
+            "preview",
+            PREVIEW_URL,
+            typst_package_cache().unwrap_or_default(),
+        ));
+        chain
+    }
+
+    /// Adds a provider, tried after the ones already in the chain.
+    pub fn push(&mut self, provider: impl PackageProvider + 'static) {
+        self.providers.push(Box::new(provider));
+    }
+
+    /// Adds a provider, tried before the ones already in the chain.
+    pub fn push_front(&mut self, provider: impl PackageProvider + 'static) {
+        self.providers.insert(0, Box::new(provider));
+    }
+}
+
+impl PackageProvider for PackageChain {
+    fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        // Chains may be nested, so the error listing the searched locations
+        // is left to the caller, see [`not_found`]
+        for provider in &self.providers {
+            match provider.resolve(spec) {
+                Err(PackageError::NotFound(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(PackageError::NotFound(spec.clone()))
+    }
+
+    fn locations(&self, spec: &PackageSpec) -> Vec<String> {
+        self.providers
+            .iter()
+            .flat_map(|provider| provider.locations(spec))
+            .collect()
+    }
+}
+
+/// A local directory containing packages of any namespace in
+/// `{namespace}/{name}/{version}`, the layout of the Typst CLI's package
+/// directories.
+pub struct LocalPackages {
+    dir: PathBuf,
+}
+
+impl LocalPackages {
+    /// Creates a provider for the packages in `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the directory of a package inside `dir`.
+    fn package_dir(&self, spec: &PackageSpec) -> PathBuf {
+        self.dir.join(package_subdir(spec))
+    }
+}
+
+impl PackageProvider for LocalPackages {
+    fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        let path = self.package_dir(spec);
+        match path.exists() {
+            true => Ok(path),
+            false => Err(PackageError::NotFound(spec.clone())),
+        }
+    }
+
+    fn locations(&self, spec: &PackageSpec) -> Vec<String> {
+        vec![self.package_dir(spec).display().to_string()]
+    }
+}
+
+/// A registry serving the package archives of a namespace over HTTP.
+///
+/// Archives are downloaded from `{url}/{name}-{version}.tar.gz` and
+/// extracted to `{cache}/{namespace}/{name}/{version}`, with the SHA-256 of
+/// the archive in `{version}.sha256` next to it.
+pub struct HttpRegistry {
+    namespace: String,
+    url: String,
+    cache: PathBuf,
+    offline: bool,
+    lock: Option<Arc<PackageLock>>,
+    fetches: Fetches,
+}
+
+impl HttpRegistry {
+    /// Creates a provider for the packages of `namespace` served at `url`,
+    /// cached in `cache`.
+    pub fn new(
+        namespace: impl Into<String>,
+        url: impl Into<String>,
+        cache: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            namespace: namespace.into(),
+            url: url.into().trim_end_matches('/').to_string(),
+            cache: cache.into(),
+            offline: false,
+            lock: None,
+            fetches: Fetches::default(),
+        }
+    }
+
+    /// Only uses packages already in the cache, never downloading any.
+    #[must_use]
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Verifies downloaded archives against a lockfile before extracting them.
+    #[must_use]
+    pub fn lock(mut self, lock: Option<Arc<PackageLock>>) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// Returns the URL of a package archive.
+    fn archive_url(&self, spec: &PackageSpec) -> String {
+        format!("{}/{}", self.url, archive_name(spec))
+    }
+}
+
+impl PackageProvider for HttpRegistry {
+    fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        if spec.namespace != self.namespace {
+            return Err(PackageError::NotFound(spec.clone()));
+        }
+        self.fetches.get_or_fetch(spec, || {
+            let path = self.cache.join(package_subdir(spec));
+            if path.exists() {
+                verify_cached(self.lock.as_deref(), spec, &path, || match self.offline {
+                    true => None,
+                    false => download(spec, &self.archive_url(spec)).ok(),
+                })?;
+                return Ok(path);
+            }
+            if self.offline {
+                return Err(PackageError::NotFound(spec.clone()));
+            }
+            let compressed = download(spec, &self.archive_url(spec))?;
+            if let Some(ref lock) = self.lock {
+                lock.verify_archive(spec, &compressed)?;
+            }
+            unpack(spec, &compressed, &path)?;
+            Ok(path)
+        })
+    }
+
+    fn locations(&self, spec: &PackageSpec) -> Vec<String> {
+        if spec.namespace != self.namespace {
+            return Vec::new();
+        }
+        let cached = self.cache.join(package_subdir(spec)).display().to_string();
+        match self.offline {
+            true => vec![cached, format!("{} (offline)", self.archive_url(spec))],
+            false => vec![cached, self.archive_url(spec)],
+        }
+    }
+}
+
+/// A local directory standing in for the registry of a namespace.
+///
+/// The directory contains extracted packages in `{name}/{version}`, or
+/// package archives named `{name}-{version}.tar.gz`, which are extracted to
+/// `{cache}/{namespace}/{name}/{version}`.
+pub struct LocalRegistry {
+    namespace: String,
+    dir: PathBuf,
+    cache: PathBuf,
+    lock: Option<Arc<PackageLock>>,
+    fetches: Fetches,
+}
+
+impl LocalRegistry {
+    /// Creates a provider for the packages of `namespace` in `dir`,
+    /// extracting archives to `cache`.
+    pub fn new(
+        namespace: impl Into<String>,
+        dir: impl Into<PathBuf>,
+        cache: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            namespace: namespace.into(),
+            dir: dir.into(),
+            cache: cache.into(),
+            lock: None,
+            fetches: Fetches::default(),
+        }
+    }
+
+    /// Verifies archives against a lockfile before extracting them.
+    #[must_use]
+    pub fn lock(mut self, lock: Option<Arc<PackageLock>>) -> Self {
+        self.lock = lock;
+        self
+    }
+}
+
+impl PackageProvider for LocalRegistry {
+    fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        if spec.namespace != self.namespace {
+            return Err(PackageError::NotFound(spec.clone()));
+        }
+        self.fetches.get_or_fetch(spec, || {
+            let extracted = self.dir.join(&*spec.name).join(spec.version.to_string());
+            if extracted.exists() {
+                return Ok(extracted);
+            }
+            let path = self.cache.join(package_subdir(spec));
+            if path.exists() {
+                verify_cached(self.lock.as_deref(), spec, &path, || {
+                    fs::read(self.dir.join(archive_name(spec))).ok()
+                })?;
+                return Ok(path);
+            }
+            let Ok(compressed) = fs::read(self.dir.join(archive_name(spec))) else {
+                return Err(PackageError::NotFound(spec.clone()));
+            };
+            if let Some(ref lock) = self.lock {
+                lock.verify_archive(spec, &compressed)?;
+            }
+            unpack(spec, &compressed, &path)?;
+            Ok(path)
+        })
+    }
+
+    fn locations(&self, spec: &PackageSpec) -> Vec<String> {
+        if spec.namespace != self.namespace {
+            return Vec::new();
+        }
+        [
+            self.dir.join(&*spec.name).join(spec.version.to_string()),
+            self.dir.join(archive_name(spec)),
+        ]
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect()
+    }
+}
+
+/// Packages defined in memory, e.g. as test fixtures.
+///
+/// Since Typst reads package files from disk, the files of a package are
+/// written to `{dir}/{namespace}/{name}/{version}` when it is first
+/// resolved.
+pub struct MemoryPackages {
+    dir: PathBuf,
+    packages: HashMap<PackageSpec, Vec<(PathBuf, Vec<u8>)>>,
+    written: Mutex<HashSet<PackageSpec>>,
+}
+
+impl MemoryPackages {
+    /// Creates an empty provider writing packages to `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            packages: HashMap::new(),
+            written: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Adds a file to a package, at a path relative to the package root.
+    ///
+    /// A `typst.toml` with the package's name and version and `lib.typ` as
+    /// entrypoint is generated unless one is added.
+    pub fn insert(
+        &mut self,
+        spec: PackageSpec,
+        path: impl Into<PathBuf>,
+        data: impl Into<Vec<u8>>,
+    ) {
+        self.packages
+            .entry(spec)
+            .or_default()
+            .push((path.into(), data.into()));
+    }
+}
+
+impl PackageProvider for MemoryPackages {
+    fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        let Some(files) = self.packages.get(spec) else {
+            return Err(PackageError::NotFound(spec.clone()));
+        };
+        let path = self.dir.join(package_subdir(spec));
+        let mut written = self.written.lock().unwrap();
+        if written.contains(spec) {
+            return Ok(path);
+        }
+
+        let manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"{}\"\nentrypoint = \"lib.typ\"\n",
+            spec.name, spec.version
+        );
+        let generated = (!files
+            .iter()
+            .any(|(file, _)| file == Path::new("typst.toml")))
+        .then(|| (PathBuf::from("typst.toml"), manifest.into_bytes()));
+        for (file, data) in files.iter().chain(generated.as_ref()) {
+            let file = path.join(file);
+            file.parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| fs::write(&file, data))
+                .map_err(|e| {
+                    PackageError::Other(Some(eco_format!(
+                        "Failed to write package {}: {}",
+                        spec,
+                        e
+                    )))
+                })?;
+        }
+        written.insert(spec.clone());
+        Ok(path)
+    }
+}
+
+/// The results of fetching packages, so that each package is fetched once
+/// even if several threads ask for it at the same time.
+#[derive(Default)]
+struct Fetches {
+    packages: Mutex<HashMap<PackageSpec, Arc<OnceLock<PackageResult<PathBuf>>>>>,
+}
+
+impl Fetches {
+    /// Returns the result of fetching a package, calling `fetch` if it was
+    /// not fetched yet. Other threads asking for the same package wait for
+    /// the fetch to complete.
+    fn get_or_fetch(
+        &self,
+        spec: &PackageSpec,
+        fetch: impl FnOnce() -> PackageResult<PathBuf>,
+    ) -> PackageResult<PathBuf> {
+        let once = self
+            .packages
+            .lock()
+            .unwrap()
+            .entry(spec.clone())
+            .or_default()
+            .clone();
+        once.get_or_init(fetch).clone()
+    }
+}
+
+/// Turns a [`PackageError::NotFound`] of `provider` into an error listing
+/// where the package was searched.
+pub(crate) fn not_found(provider: &dyn PackageProvider, error: PackageError) -> PackageError {
+    let PackageError::NotFound(ref spec) = error else {
+        return error;
+    };
+    match provider.locations(spec) {
+        locations if locations.is_empty() => error,
+        locations => PackageError::Other(Some(eco_format!(
+            "{} was not found, searched {}",
+            spec,
+            locations.join(", ")
+        ))),
+    }
+}
+
+/// Returns the path of a package in the Typst CLI's package directories.
+fn package_subdir(spec: &PackageSpec) -> String {
+    format!("{}/{}/{}", spec.namespace, spec.name, spec.version)
+}
+
+/// Returns the file name of a package archive in a registry.
+fn archive_name(spec: &PackageSpec) -> String {
+    format!("{}-{}.tar.gz", spec.name, spec.version)
+}
+
+/// Returns the path of the file storing the SHA-256 of the archive a cached
+/// package in `path` was extracted from.
+fn archive_hash_path(package: &PackageSpec, path: &Path) -> PathBuf {
+    path.with_file_name(format!("{}.sha256", package.version))
+}
+
+/// Verifies the archive a cached package in `path` was extracted from
+/// against the lockfile.
+///
+/// Packages cached by the Typst CLI have no stored archive hash, so the
+/// archive from `fetch` is hashed instead, if it is available.
+fn verify_cached(
+    lock: Option<&PackageLock>,
+    package: &PackageSpec,
+    path: &Path,
+    fetch: impl FnOnce() -> Option<Vec<u8>>,
+) -> PackageResult<()> {
+    let Some(lock) = lock else {
+        return Ok(());
+    };
+    let hash = match fs::read_to_string(archive_hash_path(package, path)) {
+        Ok(hash) => hash.trim().to_string(),
+        Err(_) => match fetch() {
+            Some(compressed) => archive_hash(&compressed),
+            None => return Ok(()),
+        },
+    };
+    lock.verify_archive_hash(package, hash)
+}
+
+/// Downloads a package archive.
+fn download(package: &PackageSpec, url: &str) -> PackageResult<Vec<u8>> {
+    let mut response = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| PackageError::NetworkFailed(Some(eco_format!("{}: {}", package, e))))?;
+
+    let mut compressed = Vec::new();
+    response.copy_to(&mut compressed).map_err(|e| {
+        PackageError::NetworkFailed(Some(eco_format!(
+            "Failed to save package {}: {}",
+            package.name,
+            e
+        )))
+    })?;
+    Ok(compressed)
+}
+
+/// Extracts a gzipped package archive to `path`.
+///
+/// The archive is extracted to a temporary sibling directory first, which is
+/// renamed to `path` once complete, so that other processes sharing the
+/// cache never see a partially extracted package. The SHA-256 of the archive
+/// is stored next to it beforehand, so that cached packages can be verified
+/// against a lockfile.
+fn unpack(package: &PackageSpec, compressed: &[u8], path: &Path) -> PackageResult<()> {
+    let decompressed = Vec::new();
+    let mut decoder = flate2::write::GzDecoder::new(decompressed);
+    decoder.write_all(compressed).map_err(|e| {
+        PackageError::MalformedArchive(Some(eco_format!(
+            "Failed to decompress package {}: {}",
+            package.name,
+            e
+        )))
+    })?;
+    decoder.try_finish().map_err(|e| {
+        PackageError::MalformedArchive(Some(eco_format!(
+            "Failed to decompress package {}: {}",
+            package.name,
+            e
+        )))
+    })?;
+    let decompressed = decoder.finish().map_err(|e| {
+        PackageError::MalformedArchive(Some(eco_format!(
+            "Failed to decompress package {}: {}",
+            package.name,
+            e
+        )))
+    })?;
+
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let temp = parent.join(format!(".{}.{}.tmp", package.version, std::process::id()));
+    let result = fs::create_dir_all(parent)
+        .and_then(|()| {
+            fs::remove_dir_all(&temp).ok();
+            tar::Archive::new(decompressed.as_slice()).unpack(&temp)
+        })
+        .map_err(|e| {
+            PackageError::MalformedArchive(Some(eco_format!(
+                "Failed to unpack package {}: {}",
+                package.name,
+                e
+            )))
+        })
+        .map(|()| {
+            // Renamed into place as well, so that it is never read partially
+            let hash = temp.with_extension("sha256");
+            fs::write(&hash, archive_hash(compressed))
+                .and_then(|()| fs::rename(&hash, archive_hash_path(package, path)))
+                .ok();
+        })
+        .and_then(|()| match fs::rename(&temp, path) {
+            // Another process extracted the package first
+            Err(_) if path.exists() => Ok(()),
+            result => result.map_err(|e| {
+                PackageError::Other(Some(eco_format!(
+                    "Failed to save package {}: {}",
+                    package.name,
+                    e
+                )))
+            }),
+        });
+    fs::remove_dir_all(&temp).ok();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a directory for a test, removing any left over.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("mdbook-typst-math-package-{}", std::process::id()))
+            .join(name);
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn nested_chain_moves_on() {
+        let dir = test_dir("nested-chain");
+        let spec: PackageSpec = "@local/macros:0.1.0".parse().unwrap();
+
+        // A user chain without the package must not stop the lookup
+        let mut inner = PackageChain::new();
+        inner.push(LocalPackages::new(dir.join("empty")));
+        let mut packages = MemoryPackages::new(dir.join("memory"));
+        packages.insert(spec.clone(), "lib.typ", "#let half = $1/2$");
+        let mut outer = PackageChain::new();
+        outer.push(inner);
+        outer.push(packages);
+
+        let path = outer.resolve(&spec).unwrap();
+        assert!(path.join("lib.typ").exists());
+    }
+
+    #[test]
+    fn chain_reports_searched_locations() {
+        let dir = test_dir("searched-locations");
+        let spec: PackageSpec = "@local/missing:0.1.0".parse().unwrap();
+        let mut inner = PackageChain::new();
+        inner.push(LocalPackages::new(dir.join("inner")));
+        let mut outer = PackageChain::new();
+        outer.push(inner);
+        outer.push(LocalPackages::new(dir.join("outer")));
+
+        let error = outer.resolve(&spec).unwrap_err();
+        assert_eq!(error, PackageError::NotFound(spec.clone()));
+        let message = not_found(&outer, error).to_string();
+        assert!(
+            message.contains("inner") && message.contains("outer"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Helpers shared by the integration tests.
+
+// Each test crate uses only some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use mdbook_preprocessor::{
+    book::{Book, BookItem},
+    parse_input, PreprocessorContext,
+};
+use serde_json::json;
+
+/// Creates an empty book directory for a test.
+pub fn book_root(name: &str) -> PathBuf {
+    let root = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::remove_dir_all(&root).ok();
+    fs::create_dir_all(root.join("src")).unwrap();
+    root
+}
+
+/// Parses the preprocessor input for a book with a single chapter, with the
+/// given preprocessor config.
+pub fn input(root: &Path, config: serde_json::Value, content: &str) -> (PreprocessorContext, Book) {
+    let input = json!([
+        {
+            "root": root,
+            "config": {
+                "book": { "title": "Test" },
+                "preprocessor": { "typst-math": config },
+            },
+            "renderer": "html",
+            "mdbook_version": mdbook_preprocessor::MDBOOK_VERSION,
+        },
+        {
+            "items": [{
+                "Chapter": {
+                    "name": "Chapter",
+                    "content": content,
+                    "number": null,
+                    "sub_items": [],
+                    "path": "chapter.md",
+                    "source_path": "chapter.md",
+                    "parent_names": [],
+                }
+            }]
+        }
+    ]);
+    parse_input(input.to_string().as_bytes()).unwrap()
+}
+
+/// Returns the content of the only chapter of a book.
+pub fn content(book: &Book) -> &str {
+    match book.items.first() {
+        Some(BookItem::Chapter(chapter)) => &chapter.content,
+        _ => panic!("the chapter is missing"),
+    }
+}
@@ -0,0 +1,47 @@
+//! Denies reading files outside of the project root.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{book_root, input};
+use mdbook_preprocessor::Preprocessor;
+use mdbook_typst_math::{RenderError, TypstProcessor};
+
+/// Renders a block reading `path`, returning the error message.
+fn read_error(name: &str, path: &str) -> String {
+    let root = book_root(name);
+    fs::write(root.join("secret.txt"), "secret").unwrap();
+    std::os::unix::fs::symlink(root.join("secret.txt"), root.join("src/link.txt")).unwrap();
+
+    let content = format!("$$\n#read(\"{path}\")\n$$\n");
+    let (ctx, book) = input(&root, serde_json::json!({}), &content);
+    let error = TypstProcessor::new().run(&ctx, book).unwrap_err();
+    let error = error.downcast_ref::<RenderError>().unwrap();
+    assert_eq!(error.diagnostics.len(), 1, "{:?}", error.diagnostics);
+    error.diagnostics[0].message.clone()
+}
+
+#[test]
+fn parent_escape_is_denied() {
+    let message = read_error("access-parent", "../secret.txt");
+    assert!(message.contains("access denied"), "{message}");
+}
+
+#[test]
+fn symlink_escape_is_denied() {
+    let message = read_error("access-symlink", "link.txt");
+    assert!(message.contains("access denied"), "{message}");
+}
+
+#[test]
+fn absolute_path_is_denied() {
+    // Absolute paths are resolved against the project root
+    let message = read_error("access-absolute", "/etc/passwd");
+    let searched = Path::new(env!("CARGO_TARGET_TMPDIR")).join("access-absolute/src/etc/passwd");
+    assert!(message.starts_with("file not found"), "{message}");
+    assert!(
+        message.contains(&searched.display().to_string()),
+        "{message}"
+    );
+}
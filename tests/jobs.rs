@@ -0,0 +1,25 @@
+//! Renders the same book serially and in parallel.
+
+mod common;
+
+use common::{book_root, content, input};
+use mdbook_preprocessor::Preprocessor;
+use mdbook_typst_math::TypstProcessor;
+
+/// Renders a chapter with many blocks on `jobs` worker threads.
+fn render(jobs: usize) -> String {
+    let root = book_root(&format!("jobs-{jobs}"));
+    let chapter: String = (0..32)
+        .map(|i| format!("Inline $x^{i}$ and display\n\n$$\nsum_(k=0)^{i} k\n$$\n\n"))
+        .collect();
+    let (ctx, book) = input(&root, serde_json::json!({ "jobs": jobs }), &chapter);
+    let book = TypstProcessor::new().run(&ctx, book).unwrap();
+    content(&book).to_string()
+}
+
+#[test]
+fn parallel_output_matches_serial() {
+    let serial = render(1);
+    assert_eq!(serial.matches("<svg").count(), 64);
+    assert_eq!(render(8), serial);
+}
@@ -0,0 +1,29 @@
+//! Renders a book through the library with an in-memory package provider.
+
+mod common;
+
+use common::{book_root, content, input};
+use mdbook_preprocessor::Preprocessor;
+use mdbook_typst_math::{MemoryPackages, TypstProcessor};
+
+#[test]
+fn renders_with_memory_packages() {
+    let root = book_root("memory-packages");
+    let (ctx, book) = input(
+        &root,
+        serde_json::json!({ "offline": true }),
+        "$$\n#import \"@local/macros:0.1.0\": half\nx = #half\n$$\n",
+    );
+
+    let mut packages = MemoryPackages::new(root.join("packages"));
+    packages.insert(
+        "@local/macros:0.1.0".parse().unwrap(),
+        "lib.typ",
+        "#let half = $1/2$",
+    );
+    let processor = TypstProcessor::new().with_package_provider(packages);
+    let book = processor.run(&ctx, book).unwrap();
+
+    assert!(content(&book).contains("<svg"), "{}", content(&book));
+    assert!(root.join("packages/local/macros/0.1.0/typst.toml").exists());
+}
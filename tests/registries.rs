@@ -0,0 +1,88 @@
+//! Resolves packages from the registries configured in `book.toml`.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use common::{book_root, content, input};
+use mdbook_preprocessor::{book::Book, Preprocessor};
+use mdbook_typst_math::{RenderError, TypstProcessor};
+use serde_json::json;
+
+/// A package importing nothing, defining `half`.
+const LIB: &str = "#let half = $1/2$";
+
+/// Runs the preprocessor on a chapter importing `package`, with the given
+/// preprocessor config.
+fn render(
+    root: &Path,
+    config: serde_json::Value,
+    package: &str,
+) -> mdbook_preprocessor::errors::Result<Book> {
+    let mut config = config;
+    config["cache"] = json!("cache");
+    let content = format!("$$\n#import \"{package}\": half\nx = #half\n$$\n");
+    let (ctx, book) = input(root, config, &content);
+    TypstProcessor::new().run(&ctx, book)
+}
+
+/// Writes the files of a package to `dir`.
+fn write_package(dir: &Path, name: &str) {
+    fs::create_dir_all(dir).unwrap();
+    let manifest =
+        format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nentrypoint = \"lib.typ\"\n");
+    fs::write(dir.join("typst.toml"), manifest).unwrap();
+    fs::write(dir.join("lib.typ"), LIB).unwrap();
+}
+
+#[test]
+fn directory_registry() {
+    let root = book_root("directory-registry");
+    write_package(&root.join("registry/macros/0.1.0"), "macros");
+
+    let config = json!({ "registries": { "acme": "registry" } });
+    let book = render(&root, config, "@acme/macros:0.1.0").unwrap();
+    assert!(content(&book).contains("<svg"), "{}", content(&book));
+}
+
+#[test]
+fn file_url_registry() {
+    let root = book_root("file-url-registry");
+    let package = root.join("package");
+    write_package(&package, "macros");
+    let registry = root.join("registry");
+    fs::create_dir_all(&registry).unwrap();
+    let archive = fs::File::create(registry.join("macros-0.1.0.tar.gz")).unwrap();
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        archive,
+        flate2::Compression::default(),
+    ));
+    builder.append_dir_all(".", &package).unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+
+    let url = format!("file://{}", registry.display());
+    let config = json!({ "registries": { "acme": url } });
+    let book = render(&root, config, "@acme/macros:0.1.0").unwrap();
+    assert!(content(&book).contains("<svg"), "{}", content(&book));
+    // Archives are extracted to the package cache
+    assert!(root.join("cache/acme/macros/0.1.0/lib.typ").exists());
+}
+
+#[test]
+fn offline_error() {
+    let root = book_root("offline-registry");
+
+    let config = json!({
+        "offline": true,
+        "registries": { "acme": "http://127.0.0.1:9/acme" },
+    });
+    let error = render(&root, config, "@acme/macros:0.1.0").unwrap_err();
+    let error = error.downcast_ref::<RenderError>().unwrap();
+    let message = &error.diagnostics[0].message;
+    assert!(
+        message.contains("@acme/macros:0.1.0 was not found")
+            && message.contains("http://127.0.0.1:9/acme/macros-0.1.0.tar.gz (offline)"),
+        "{}",
+        message
+    );
+}
@@ -0,0 +1,69 @@
+//! Inspects the diagnostics of a failed build through the library.
+
+mod common;
+
+use common::{book_root, input};
+use mdbook_preprocessor::Preprocessor;
+use mdbook_typst_math::{RenderError, Severity, TypstProcessor};
+
+#[test]
+fn failed_build_carries_diagnostics() {
+    let root = book_root("render-error");
+    let (ctx, book) = input(&root, serde_json::json!({}), "Inline $x + #foo$ math.\n");
+
+    let error = TypstProcessor::new().run(&ctx, book).unwrap_err();
+    let error = error.downcast_ref::<RenderError>().unwrap();
+    assert_eq!(error.diagnostics.len(), 1);
+    let diagnostic = &error.diagnostics[0];
+    assert_eq!(diagnostic.severity, Severity::Error);
+    assert!(diagnostic.message.contains("foo"), "{}", diagnostic.message);
+    assert_eq!(diagnostic.file.as_deref(), Some("chapter.md"));
+    assert_eq!(diagnostic.line, Some(1));
+}
+
+#[test]
+fn diagnostics_point_into_the_markdown() {
+    let root = book_root("render-error-locations");
+    let content = "# Chapter\n\nSome $x + #foo$ math.\n\n$$\na +\n  #bar\n$$\n";
+    let (ctx, book) = input(&root, serde_json::json!({}), content);
+
+    let error = TypstProcessor::new().run(&ctx, book).unwrap_err();
+    let error = error.downcast_ref::<RenderError>().unwrap();
+    let locations: Vec<_> = error
+        .diagnostics
+        .iter()
+        .map(|diagnostic| {
+            (
+                diagnostic.message.as_str(),
+                diagnostic.line,
+                diagnostic.column,
+            )
+        })
+        .collect();
+    assert_eq!(
+        locations,
+        [
+            ("unknown variable: foo", Some(3), Some(12)),
+            ("unknown variable: bar", Some(7), Some(4)),
+        ]
+    );
+}
+
+#[test]
+fn timeout_points_at_the_block() {
+    let root = book_root("render-error-timeout");
+    let content = "# Chapter\n\n$$\n#for i in range(100000000) {}\n$$\n";
+    let (ctx, book) = input(&root, serde_json::json!({ "timeout": 0.01 }), content);
+
+    let error = TypstProcessor::new().run(&ctx, book).unwrap_err();
+    let error = error.downcast_ref::<RenderError>().unwrap();
+    assert_eq!(error.diagnostics.len(), 1);
+    let diagnostic = &error.diagnostics[0];
+    assert!(
+        diagnostic.message.contains("timed out"),
+        "{}",
+        diagnostic.message
+    );
+    assert_eq!(diagnostic.file.as_deref(), Some("chapter.md"));
+    assert_eq!((diagnostic.line, diagnostic.column), (Some(3), Some(1)));
+}